@@ -1,89 +1,943 @@
 pub mod albedo_utils;
+pub mod light_utils;
 pub mod normal_utils;
+pub mod output_utils;
 pub mod radiance_map;
+pub mod reflectance_model;
+pub mod residual_utils;
 
-use image::{DynamicImage, RgbImage};
+use image::{DynamicImage, GrayImage};
 use na::{Vector2, Vector3};
+use std::time::{Duration, Instant};
 extern crate nalgebra as na;
 
 use normal_utils::*;
 use radiance_map::*;
 
+/// What to do when fewer than three usable images remain,
+/// which isn't enough to solve for normals
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TooFewLights {
+    /// Return an error
+    #[default]
+    Error,
+    /// Approximate the normals from the brightness gradient of the
+    /// remaining images (see normal_utils::normals_from_brightness)
+    BrightnessGradient,
+}
+
+/// A rectangle of pixels within an image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Crops each image to its own rectangle, such as to align captures
+/// of a subject that moved between shots into a common frame.
+/// Every rectangle must have the same size and fit in its image.
+pub fn crop_images(images: &[DynamicImage], crops: &[Rect]) -> Result<Vec<DynamicImage>, String> {
+    if crops.len() != images.len() {
+        return Err("Crops don't match the number of images".to_string());
+    }
+    let mut cropped = Vec::<DynamicImage>::new();
+    for (i, (image, crop)) in images.iter().zip(crops).enumerate() {
+        if (crop.width, crop.height) != (crops[0].width, crops[0].height) {
+            return Err(format!(
+                "Crop {} is {}x{}, but crop 0 is {}x{}",
+                i, crop.width, crop.height, crops[0].width, crops[0].height
+            ));
+        }
+        if crop.x + crop.width > image.width() || crop.y + crop.height > image.height() {
+            return Err(format!("Crop {} doesn't fit in its image", i));
+        }
+        cropped.push(image.crop_imm(crop.x, crop.y, crop.width, crop.height));
+    }
+    Ok(cropped)
+}
+
+/// Options for generate_normal_map_with_config
+#[derive(Clone, Debug)]
+pub struct NormalMapConfig {
+    /// Number of threads used by the parallel stages, or None to use
+    /// one per core. The stages run on their own thread pool, rather
+    /// than rayon's global pool.
+    pub threads: Option<usize>,
+    /// Largest fraction of pixels that may fail to solve before the
+    /// whole result is rejected as an error. Defaults to 0.5.
+    pub max_fallback_fraction: f32,
+    /// Exposure settings for each image, such as from load_exposure.
+    /// When given, the images are scaled to match each other before
+    /// solving, by their exposure if all are known, and by matching
+    /// their mean brightness otherwise. Empty by default, which leaves
+    /// the images as they are.
+    pub exposures: Vec<Option<Exposure>>,
+    /// For images captured under combinations of lights, the matrix
+    /// that recovers the image under each individual light (see
+    /// radiance_map::demultiplex). None by default.
+    pub demultiplexing: Option<na::DMatrix<f32>>,
+    /// Steps applied to the radiance maps in order, after exposures
+    /// and demultiplexing but before unusable images are dropped.
+    /// Empty by default.
+    pub preprocessing: Preprocessing,
+    /// What to do when fewer than three images are usable, after
+    /// dropping those that are almost entirely black or saturated.
+    /// Defaults to returning an error.
+    pub too_few_lights: TooFewLights,
+    /// Even out the brightness gradient of each image, as from lights
+    /// close to the subject, before solving (see
+    /// radiance_map::corner_flatten_radiance). Off by default.
+    pub flatten_radiance: bool,
+    /// Subtract each pixel's minimum brightness across the images
+    /// before solving, to remove a baseline present under every light
+    /// (see radiance_map::subtract_minimum). Off by default.
+    pub subtract_minimum: bool,
+    /// Number of times the light directions and normals are
+    /// re-estimated from each other. Defaults to 4.
+    pub refinement_iterations: usize,
+    /// Stop refining once no light direction moves by more than this
+    /// many degrees in an iteration, so refinement_iterations acts as a
+    /// cap. The number of iterations run is NormalMapResult::iterations.
+    /// None (the default) runs every iteration.
+    pub convergence_threshold: Option<f32>,
+    /// Number of 3x3 smoothing passes applied to the solved normals,
+    /// to suppress noise. Defaults to 0. See
+    /// radiance_map::recommend_iterations for values suited to a
+    /// capture.
+    pub smoothing_iterations: usize,
+    /// Number of corner flattening passes applied to the normals, each
+    /// followed by reorienting them to face the camera. Defaults to 10.
+    pub flatten_iterations: usize,
+    /// A crop for each image, applied before solving (see
+    /// crop_images). Empty by default, which uses the whole images.
+    pub crops: Vec<Rect>,
+    /// Also produce NormalMapResult::magnitude_map. Off by default.
+    pub magnitude_map: bool,
+    /// Project normals facing away from the camera onto the horizon
+    /// after solving (see normal_utils::clamp_to_hemisphere).
+    /// Off by default.
+    pub clamp_hemisphere: bool,
+    /// After the first refinement iteration, only estimate the lights
+    /// from lit pixels whose confidence (see residual_utils::confidence_map)
+    /// in the previous iteration's fit is at least this, so regions
+    /// that don't fit diffuse shading can't skew the lights. None (the
+    /// default) uses every pixel.
+    pub light_confidence_threshold: Option<f32>,
+    /// The (low, high) range of radiance used to estimate the lights.
+    /// Pixels outside it, such as deep shadows and blown highlights,
+    /// are left out (see normal_utils::generate_lighting_direction_in_band).
+    /// None (the default) uses every pixel.
+    pub light_radiance_band: Option<(f32, f32)>,
+    /// Divide out the color of each image's light before converting it
+    /// to greyscale (see radiance_map::radiance_without_light_color),
+    /// for lights of different color temperatures. Off by default.
+    pub correct_light_color: bool,
+    /// Decode the images from sRGB to linear brightness as the radiance
+    /// is read (see radiance_map::linearize_image), since diffuse
+    /// shading is linear in brightness. This happens before exposures
+    /// and demultiplexing, which also assume linear brightness. Off by
+    /// default, for images that are already linear, such as EXR files
+    /// or raw captures.
+    pub linearize: bool,
+    /// Stop refining and flattening once the solve has run for this
+    /// long, keeping the best result so far. The first refinement
+    /// iteration always runs, so there is a solution to return.
+    /// None (the default) runs every iteration.
+    pub time_budget: Option<Duration>,
+    /// A background color to leave out of the solve. Pixels near it
+    /// (see radiance_map::color_key_mask) aren't used to estimate the
+    /// lights, and are given forward facing normals. None by default.
+    pub color_key: Option<ColorKey>,
+    /// A mask image the size of the (cropped) images, nonzero where
+    /// pixels should be solved. Like the color key, masked out pixels
+    /// aren't used to estimate the lights, and are given forward facing
+    /// normals. None by default. Pixels that are fully transparent in
+    /// any image are always masked out (see radiance_map::alpha_mask).
+    pub mask: Option<DynamicImage>,
+    /// The object's up vector in camera space. When given, the final
+    /// normals are rotated so this points along +z, expressing them
+    /// in the object's frame (see normal_utils::to_object_space)
+    /// rather than the camera's. None by default.
+    pub up_vector: Option<Vector3<f32>>,
+    /// Options for each pixel's solve, such as the brightness above
+    /// which an observation is treated as specular and left out.
+    /// Pixels left with too few observations keep their normal from
+    /// the previous iteration, starting from dome_normals.
+    pub solve_options: SolveOptions,
+    /// How the normal map is encoded, such as whether +Y points down
+    /// the image (DirectX, the default) or up it (OpenGL). The 16 bit
+    /// normal map only uses the convention.
+    pub image_options: output_utils::NormalImageOptions,
+    /// Whether the normal map has an alpha channel. With
+    /// AlphaMode::Mask, pixels masked out by the color key or mask are
+    /// transparent. Defaults to RGB without alpha.
+    pub output_alpha: output_utils::AlphaMode,
+}
+
+/// The phases of the solve reported in a ProgressEvent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressPhase {
+    /// Estimating the light of each image from the current normals
+    LightEstimation,
+    /// Solving the normals from the current lights
+    NormalSolve,
+    /// Corner flattening the normals
+    Flatten,
+}
+
+/// How far the solve has got, passed to the callback of
+/// generate_normal_map_with_progress. Light estimation and the normal
+/// solve alternate, each reporting once per refinement iteration, and
+/// flattening reports once per pass.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgressEvent {
+    pub phase: ProgressPhase,
+    /// Fraction of the phase done, from 0 to 1. A phase that finishes
+    /// early, by converging or running out of time, stops short of 1.
+    pub fraction: f32,
+}
+
+impl Default for NormalMapConfig {
+    fn default() -> Self {
+        Self {
+            threads: None,
+            max_fallback_fraction: 0.5,
+            exposures: Vec::new(),
+            demultiplexing: None,
+            preprocessing: Preprocessing::default(),
+            too_few_lights: TooFewLights::Error,
+            flatten_radiance: false,
+            subtract_minimum: false,
+            refinement_iterations: 4,
+            convergence_threshold: None,
+            smoothing_iterations: 0,
+            flatten_iterations: 10,
+            crops: Vec::new(),
+            magnitude_map: false,
+            clamp_hemisphere: false,
+            light_confidence_threshold: None,
+            light_radiance_band: None,
+            correct_light_color: false,
+            linearize: false,
+            time_budget: None,
+            color_key: None,
+            mask: None,
+            up_vector: None,
+            solve_options: SolveOptions::default(),
+            image_options: Default::default(),
+            output_alpha: output_utils::AlphaMode::None,
+        }
+    }
+}
+
+/// A normal map, along with details of how it was solved
+pub struct NormalMapResult {
+    pub normal_map: DynamicImage,
+    /// Mean angle in degrees that the normals moved in each
+    /// refinement iteration that ran, which shrinks as the solve
+    /// converges
+    pub iteration_deltas: Vec<f32>,
+    /// When requested, a greyscale image of the length of each pixel's
+    /// least squares solution before normalizing (see
+    /// normal_utils::least_squares_magnitudes), clamped to [0, 1].
+    /// Unlike the residual based confidence, this is high wherever the
+    /// diffuse response is strong.
+    pub magnitude_map: Option<DynamicImage>,
+    /// Whether the time budget ran out before every iteration finished
+    pub time_limited: bool,
+    /// Estimated direction of the light in each image, in the same
+    /// order as the images. Images left out of the solve for being
+    /// almost entirely black or saturated are skipped.
+    pub light_directions: Vec<Vector3<f32>>,
+    /// Whether refinement stopped early because the lights moved less
+    /// than NormalMapConfig::convergence_threshold
+    pub converged: bool,
+    /// How well the final normals explain the images, from 1 (a
+    /// perfect fit) down to 0, as the mean confidence (see
+    /// residual_utils::confidence_map) of the subject's pixels. 0 when
+    /// there were too few lights to solve.
+    pub quality: f32,
+}
+
+impl NormalMapResult {
+    /// Number of refinement iterations that ran, which may be fewer
+    /// than requested when the solve converged or ran out of time
+    pub fn iterations(&self) -> usize {
+        self.iteration_deltas.len()
+    }
+}
+
 pub fn generate_normal_map(images: &[DynamicImage]) -> Result<DynamicImage, String> {
+    generate_normal_map_with_progress(images, |_| {})
+}
+
+/// Like generate_normal_map, but calls `callback` as the solve
+/// progresses (see ProgressEvent), such as to draw a progress bar.
+/// The callback may be called from any of the solve's threads.
+pub fn generate_normal_map_with_progress(
+    images: &[DynamicImage],
+    callback: impl Fn(ProgressEvent) + Sync,
+) -> Result<DynamicImage, String> {
+    Ok(
+        generate_normal_map_result_with_progress(images, &NormalMapConfig::default(), callback)?
+            .normal_map,
+    )
+}
+
+pub fn generate_normal_map_with_config(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+) -> Result<DynamicImage, String> {
+    Ok(generate_normal_map_result(images, config)?.normal_map)
+}
+
+/// Like generate_normal_map_with_config,
+/// but also returns details of the solve.
+pub fn generate_normal_map_result(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+) -> Result<NormalMapResult, String> {
+    generate_normal_map_result_with_progress(images, config, |_| {})
+}
+
+/// Like generate_normal_map_result, but reports progress like
+/// generate_normal_map_with_progress
+pub fn generate_normal_map_result_with_progress(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+    callback: impl Fn(ProgressEvent) + Sync,
+) -> Result<NormalMapResult, String> {
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads.unwrap_or(0))
+        .build()
+        .map_err(|err| err.to_string())?;
+    thread_pool.install(|| solve_normal_map(images, config, &callback))
+}
+
+/// Like generate_normal_map_with_config, but the normal map is a 16 bit
+/// RGB image (see output_utils::normals_to_image_16bit), which avoids
+/// visible banding on smooth surfaces.
+pub fn generate_normal_map_16bit(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+) -> Result<DynamicImage, String> {
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads.unwrap_or(0))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let fields = thread_pool.install(|| solve_fields(images, config, &|_| {}))?;
+    output_utils::normals_to_image_16bit(
+        &fields.normals,
+        &fields.size,
+        config.image_options.convention,
+    )
+}
+
+/// The fields found by solving for normals, before encoding
+struct SolvedFields {
+    normals: NormalMatrix,
+    /// Radiance maps that were solved from, with their estimated lights
+    radiance_maps: Vec<RadianceMap>,
+    size: Vector2<usize>,
+    iteration_deltas: Vec<f32>,
+    time_limited: bool,
+    converged: bool,
+    /// See NormalMapResult::quality
+    quality: f32,
+    /// Which pixels show the subject, when masking with a color key
+    subject: Option<Vec<bool>>,
+}
+
+/// The mean confidence of the subject's pixels (see
+/// NormalMapResult::quality)
+fn fit_quality(
+    normals: &NormalMatrix,
+    radiance_maps: &[RadianceMap],
+    subject: Option<&[bool]>,
+) -> f32 {
+    let albedo = albedo_utils::solve_albedo(radiance_maps);
+    let residuals = residual_utils::reconstruction_residuals(normals, &albedo, radiance_maps);
+    let confidence = residual_utils::confidence_map(&residuals, &vec![false; normals.nrows()]);
+    let pixels: Vec<f32> = confidence
+        .iter()
+        .enumerate()
+        .filter(|(pixel, _)| subject.is_none_or(|subject| subject[*pixel]))
+        .map(|(_, confidence)| *confidence)
+        .collect();
+    if pixels.is_empty() {
+        return 0.0;
+    }
+    pixels.iter().sum::<f32>() / pixels.len() as f32
+}
+
+fn solve_normal_map(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<NormalMapResult, String> {
+    let fields = solve_fields(images, config, progress)?;
+    let size = fields.size;
+    // Too few lights fall back to brightness gradients, which have no
+    // least squares solutions
+    let magnitude_map = if config.magnitude_map && fields.radiance_maps.len() >= 3 {
+        let magnitudes = least_squares_magnitudes(&fields.radiance_maps, &config.solve_options);
+        let bytes: Vec<u8> = magnitudes
+            .iter()
+            .map(|magnitude| (magnitude.clamp(0.0, 1.0) * 255.0).round() as u8)
+            .collect();
+        GrayImage::from_vec(size[0] as u32, size[1] as u32, bytes).map(DynamicImage::from)
+    } else {
+        None
+    };
+
+    Ok(NormalMapResult {
+        normal_map: output_utils::apply_alpha_mode(
+            output_utils::normals_to_image(&fields.normals, &size, config.image_options)?,
+            config.output_alpha,
+            fields.subject.as_deref(),
+        ),
+        iteration_deltas: fields.iteration_deltas,
+        magnitude_map,
+        time_limited: fields.time_limited,
+        converged: fields.converged,
+        quality: fields.quality,
+        light_directions: fields
+            .radiance_maps
+            .iter()
+            .map(|radiance_map| radiance_map.lighting_direction)
+            .collect(),
+    })
+}
+
+fn solve_fields(
+    images: &[DynamicImage],
+    config: &NormalMapConfig,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<SolvedFields, String> {
+    let start = Instant::now();
+    let out_of_time = || {
+        config
+            .time_budget
+            .is_some_and(|budget| start.elapsed() > budget)
+    };
     if images.is_empty() {
         return Err("No images provided".to_string());
     }
+    let cropped_images;
+    let images = if config.crops.is_empty() {
+        images
+    } else {
+        cropped_images = crop_images(images, &config.crops)?;
+        &cropped_images[..]
+    };
     let size = Vector2::new(images[0].width() as usize, images[0].height() as usize);
+    let mut subject = config
+        .color_key
+        .map(|color_key| color_key_mask(images, &color_key));
+    if let Some(mask) = &config.mask {
+        if mask.width() as usize != size.x || mask.height() as usize != size.y {
+            return Err(format!(
+                "The mask is {}x{}, but the images are {}x{}",
+                mask.width(),
+                mask.height(),
+                size.x,
+                size.y
+            ));
+        }
+        let mask = image_mask(mask);
+        subject = Some(match subject {
+            Some(keyed) => keyed.iter().zip(&mask).map(|(a, b)| *a && *b).collect(),
+            None => mask,
+        });
+    }
+    for alpha in images.iter().filter_map(alpha_mask) {
+        subject = Some(match subject {
+            Some(kept) => kept.iter().zip(&alpha).map(|(a, b)| *a && *b).collect(),
+            None => alpha,
+        });
+    }
+    let reorient = |normals: &NormalMatrix| match &subject {
+        Some(subject) => normal_utils::reorient_subject_normals(normals, subject),
+        None => normal_utils::reorient_normals(normals),
+    };
 
     // Initialize maps
     let mut radiance_maps = Vec::<RadianceMap>::new();
     for image in images {
-        radiance_maps.push(RadianceMap::from(image.to_owned()));
-    }
-
-    let mut initial_normal_map = Vec::<f32>::new();
-    for y in 0..size[1] {
-        for x in 0..size[0] {
-            initial_normal_map.extend_from_slice(
-                Vector3::new(
-                    x as f32 - size[0] as f32 / 2.0,
-                    y as f32 - size[1] as f32 / 2.0,
-                    size[0].max(size[1]) as f32,
-                )
-                .normalize()
-                .as_slice(),
-            );
+        let linear;
+        let image = if config.linearize {
+            linear = linearize_image(image);
+            &linear
+        } else {
+            image
+        };
+        radiance_maps.push(if config.correct_light_color {
+            radiance_without_light_color(image)
+        } else {
+            RadianceMap::from(image.to_owned())
+        });
+    }
+    if !config.exposures.is_empty() {
+        if config.exposures.len() != images.len() {
+            return Err("Exposures don't match the number of images".to_string());
+        }
+        apply_exposures(&mut radiance_maps, &config.exposures);
+    }
+    if let Some(demultiplexing) = &config.demultiplexing {
+        radiance_maps = demultiplex(&radiance_maps, demultiplexing)?;
+    }
+    config.preprocessing.apply(&mut radiance_maps)?;
+    radiance_maps.retain(is_usable);
+    if radiance_maps.len() < 3 {
+        if radiance_maps.is_empty() || config.too_few_lights == TooFewLights::Error {
+            return Err(format!(
+                "Only {} usable images, but at least 3 are needed to solve normals",
+                radiance_maps.len()
+            ));
         }
+        let mut mean_radiance = RadianceMatrix::zeros(size.product());
+        for radiance_map in &radiance_maps {
+            mean_radiance += &radiance_map.radiance / radiance_maps.len() as f32;
+        }
+        return Ok(SolvedFields {
+            normals: normals_from_brightness(&mean_radiance, &size, 4.0),
+            radiance_maps,
+            size,
+            iteration_deltas: Vec::new(),
+            time_limited: false,
+            converged: false,
+            quality: 0.0,
+            subject,
+        });
+    }
+    if config.flatten_radiance {
+        radiance_maps.iter_mut().for_each(corner_flatten_radiance);
     }
+    if config.subtract_minimum {
+        subtract_minimum(&mut radiance_maps);
+    }
+
+    let mut normal_matrix = dome_normals(&size);
+    let mut iteration_deltas = Vec::<f32>::new();
+    let mut time_limited = false;
+    let mut converged = false;
+    let report = |phase: ProgressPhase, done: usize, total: usize| {
+        progress(ProgressEvent {
+            phase,
+            fraction: done as f32 / total as f32,
+        })
+    };
 
-    let mut normal_matrix = NormalMatrix::from_row_slice(&initial_normal_map);
+    // Pixels trusted for estimating the lights, or None for all of them
+    let is_subject = |pixel: usize| subject.as_ref().is_none_or(|subject| subject[pixel]);
+    let mut confident_pixels: Option<Vec<usize>> = subject.as_ref().map(|_| {
+        (0..size.product())
+            .filter(|pixel| is_subject(*pixel))
+            .collect()
+    });
 
-    for _ in 0..4 {
+    for iteration in 0..config.refinement_iterations {
+        if iteration > 0 && out_of_time() {
+            time_limited = true;
+            break;
+        }
+        let previous_lights: Vec<Vector3<f32>> = radiance_maps
+            .iter()
+            .map(|radiance_map| radiance_map.lighting_direction)
+            .collect();
         // Generate new radiance maps
         for radiance_map in &mut radiance_maps {
-            let est_light_direction =
-                generate_lighting_direction(&normal_matrix, &radiance_map.radiance);
-            radiance_map.lighting_direction = est_light_direction;
+            let estimate = |normals: &NormalMatrix, radiance: &RadianceMatrix| match config
+                .light_radiance_band
+            {
+                None => generate_lighting_direction(normals, radiance),
+                Some((low, high)) => {
+                    generate_lighting_direction_in_band(normals, radiance, low, high)
+                }
+            };
+            let est_light_direction = match &confident_pixels {
+                None => estimate(&normal_matrix, &radiance_map.radiance),
+                Some(pixels) => estimate(
+                    &normal_matrix.select_rows(pixels),
+                    &radiance_map.radiance.select_rows(pixels),
+                ),
+            };
+            radiance_map.lighting_direction = est_light_direction?;
         }
+        report(
+            ProgressPhase::LightEstimation,
+            iteration + 1,
+            config.refinement_iterations,
+        );
         // Generate new normal maps
-        let est_normal_map = generate_normals(&radiance_maps);
+        let (mut est_normal_map, stats) =
+            generate_normals_with_options(&radiance_maps, &config.solve_options);
+        if stats.fallback_fraction() > config.max_fallback_fraction {
+            return Err(format!(
+                "{:.0}% of pixels could not be solved, so the normal map would be unreliable",
+                stats.fallback_fraction() * 100.0
+            ));
+        }
+        if let Some(threshold) = config.light_confidence_threshold {
+            let albedo = albedo_utils::solve_albedo(&radiance_maps);
+            let residuals =
+                residual_utils::reconstruction_residuals(&est_normal_map, &albedo, &radiance_maps);
+            let confidence =
+                residual_utils::confidence_map(&residuals, &vec![false; size.product()]);
+            let pixels: Vec<usize> = (0..size.product())
+                .filter(|pixel| {
+                    is_subject(*pixel) && albedo[*pixel] > 0.0 && confidence[*pixel] >= threshold
+                })
+                .collect();
+            // Keep the previous pixels if too few fit well
+            if pixels.len() >= 3 {
+                confident_pixels = Some(pixels);
+            }
+        }
+        // Pixels that couldn't be solved keep their previous normal
+        for pixel in stats.fallback_pixels {
+            est_normal_map.set_row(pixel, &normal_matrix.row(pixel));
+        }
         // Reorient the normal map to face towards the camera
-        let new_normal_map = normal_utils::reorient_normals(&est_normal_map);
+        let new_normal_map = reorient(&est_normal_map);
+        let new_normal_map = match &subject {
+            Some(subject) => flat_fill(&new_normal_map, subject),
+            None => new_normal_map,
+        };
+        iteration_deltas.push(mean_angle_between(&normal_matrix, &new_normal_map));
         normal_matrix = new_normal_map;
+        report(
+            ProgressPhase::NormalSolve,
+            iteration + 1,
+            config.refinement_iterations,
+        );
+
+        // The first lights are estimated from the initial dome, so
+        // there is nothing to compare them to
+        if let (Some(threshold), true) = (config.convergence_threshold, iteration > 0) {
+            let largest_change = previous_lights
+                .iter()
+                .zip(&radiance_maps)
+                .map(|(previous, radiance_map)| {
+                    previous
+                        .angle(&radiance_map.lighting_direction)
+                        .to_degrees()
+                })
+                .fold(0.0, f32::max);
+            if largest_change < threshold {
+                converged = true;
+                break;
+            }
+        }
     }
 
-    for radiance_map in &radiance_maps {
-        println!("Est light direction: {}", radiance_map.lighting_direction);
+    for _ in 0..config.smoothing_iterations {
+        normal_matrix = normal_utils::smooth_normals(&normal_matrix, &size);
     }
 
     // Flatten normal map
     let mut flattened_normals = normal_matrix;
-    for _ in 0..10 {
+    for pass in 0..config.flatten_iterations {
+        if out_of_time() {
+            time_limited = true;
+            break;
+        }
         flattened_normals = normal_utils::corner_flatten(&flattened_normals, &size);
         // Reorient the normal map to face towards the camera
-        flattened_normals = normal_utils::reorient_normals(&flattened_normals);
+        flattened_normals = reorient(&flattened_normals);
+        report(ProgressPhase::Flatten, pass + 1, config.flatten_iterations);
     }
 
     // Write flattened normal map
-    let normal_bytes: Vec<u8> = flattened_normals
-        .transpose()
+    if config.clamp_hemisphere {
+        flattened_normals = clamp_to_hemisphere(&flattened_normals);
+    }
+    // Judge the fit while the normals are still in camera space
+    let quality = fit_quality(&flattened_normals, &radiance_maps, subject.as_deref());
+    if let Some(up) = &config.up_vector {
+        flattened_normals = to_object_space(&flattened_normals, up)
+            .ok_or_else(|| "The up vector must not be zero".to_string())?;
+    }
+    if let Some(subject) = &subject {
+        flattened_normals = flat_fill(&flattened_normals, subject);
+    }
+
+    Ok(SolvedFields {
+        normals: flattened_normals,
+        radiance_maps,
+        size,
+        iteration_deltas,
+        time_limited,
+        converged,
+        quality,
+        subject,
+    })
+}
+
+/// Everything found by solve_all, as matrices rather than images
+pub struct SolveOutput {
+    /// Unit normal of each pixel, one per row, as in generate_normal_map
+    pub normals: NormalMatrix,
+    /// Greyscale albedo, with a row for each row of the image
+    pub albedo: na::DMatrix<f32>,
+    /// How well each pixel fits diffuse shading, from 1 (a perfect
+    /// fit) to 0 (see residual_utils::confidence_map)
+    pub confidence: RadianceMatrix,
+    /// Estimated direction of the light in each usable image
+    pub lights: Vec<Vector3<f32>>,
+    pub size: Vector2<usize>,
+}
+
+/// Solves for the normals, albedo, confidence, and lights all at once,
+/// keeping them as nalgebra matrices for further processing, rather
+/// than encoding them as images.
+pub fn solve_all(images: &[DynamicImage], config: &NormalMapConfig) -> Result<SolveOutput, String> {
+    let thread_pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.threads.unwrap_or(0))
+        .build()
+        .map_err(|err| err.to_string())?;
+    let fields = thread_pool.install(|| solve_fields(images, config, &|_| {}))?;
+    let size = fields.size;
+
+    let albedo = albedo_utils::solve_albedo(&fields.radiance_maps);
+    let residuals =
+        residual_utils::reconstruction_residuals(&fields.normals, &albedo, &fields.radiance_maps);
+    let confidence = residual_utils::confidence_map(&residuals, &vec![false; size.product()]);
+    Ok(SolveOutput {
+        normals: fields.normals,
+        albedo: na::DMatrix::from_row_slice(size[1], size[0], albedo.as_slice()),
+        confidence,
+        lights: fields
+            .radiance_maps
+            .iter()
+            .map(|radiance_map| radiance_map.lighting_direction)
+            .collect(),
+        size,
+    })
+}
+
+/// Generates a normal map from images lit by known lights, where
+/// `lights[i]` is the direction of the light for the ith image.
+///
+/// The lights aren't estimated, so there is no refinement or
+/// flattening, and the normals are solved directly. The directions
+/// don't need to be normalized.
+pub fn generate_normal_map_calibrated(
+    images: &[DynamicImage],
+    lights: &[Vector3<f32>],
+) -> Result<DynamicImage, String> {
+    let lights = normalized_lights(images, lights)?;
+    let size = Vector2::new(images[0].width() as usize, images[0].height() as usize);
+    let mut radiance_maps = Vec::<RadianceMap>::new();
+    for (image, light) in images.iter().zip(lights) {
+        let mut radiance_map = RadianceMap::from(image.to_owned());
+        radiance_map.lighting_direction = light;
+        radiance_maps.push(radiance_map);
+    }
+    let normals = generate_normals(&radiance_maps);
+    output_utils::normals_to_image(&normals, &size, Default::default())
+}
+
+/// Like generate_normal_map_calibrated, but solves from all three color
+/// channels of each image (see normal_utils::generate_normals_color),
+/// which is more robust for strongly colored subjects than solving from
+/// their greyscale brightness.
+pub fn generate_normal_map_color_calibrated(
+    images: &[DynamicImage],
+    lights: &[Vector3<f32>],
+) -> Result<DynamicImage, String> {
+    let lights = normalized_lights(images, lights)?;
+    let size = Vector2::new(images[0].width() as usize, images[0].height() as usize);
+    let channel_maps: Vec<[RadianceMap; 3]> = images
         .iter()
-        .map(|channel| (channel * 128.0 + 128.0) as u8)
+        .zip(lights)
+        .map(|(image, light)| {
+            let mut channels = color_channels(image);
+            for channel in &mut channels {
+                channel.lighting_direction = light;
+            }
+            channels
+        })
         .collect();
-    let normal_output = match RgbImage::from_vec(size[0] as u32, size[1] as u32, normal_bytes) {
-        None => Err("Normal output wasn't the right size".to_string()),
-        Some(x) => Ok(x),
-    }?;
-    Ok(normal_output.into())
+    let (normals, _) = generate_normals_color(&channel_maps, &SolveOptions::default());
+    output_utils::normals_to_image(&normals, &size, Default::default())
+}
+
+/// Checks there is a light for each image, and normalizes them
+fn normalized_lights(
+    images: &[DynamicImage],
+    lights: &[Vector3<f32>],
+) -> Result<Vec<Vector3<f32>>, String> {
+    if images.is_empty() {
+        return Err("No images provided".to_string());
+    }
+    if images.len() != lights.len() {
+        return Err(format!(
+            "Expected {} images to match the light directions, got {}",
+            lights.len(),
+            images.len()
+        ));
+    }
+    lights
+        .iter()
+        .map(|light| {
+            light
+                .try_normalize(f32::EPSILON)
+                .ok_or("Light directions must not be zero".to_string())
+        })
+        .collect()
+}
+
+/// Generates normal maps for several subjects photographed with the
+/// same set of lights, where `light_dirs[i]` is the direction of the
+/// light for the ith image of every subject.
+///
+/// The lighting is known, so no light estimation or flattening is
+/// done, and the pseudo-inverse of the lighting directions is only
+/// computed once for the whole batch.
+pub fn generate_batch(
+    subjects: &[Vec<DynamicImage>],
+    light_dirs: &[Vector3<f32>],
+) -> Vec<Result<DynamicImage, String>> {
+    let pseudo_inverse = light_dirs
+        .iter()
+        .map(|light_dir| {
+            light_dir
+                .try_normalize(f32::EPSILON)
+                .ok_or("Light directions must not be zero".to_string())
+        })
+        .collect::<Result<Vec<_>, String>>()
+        .and_then(|light_dirs| {
+            let light_matrix: Vec<f32> = light_dirs
+                .iter()
+                .flat_map(|light_dir| light_dir.iter().copied())
+                .collect();
+            normal_utils::pseudo_inverse(&NormalMatrix::from_row_slice(&light_matrix))
+                .ok_or("Light directions are underconstrained".to_string())
+        });
+
+    subjects
+        .iter()
+        .map(|images| {
+            let pseudo_inverse = pseudo_inverse.as_ref().map_err(|error| error.clone())?;
+            if images.is_empty() {
+                return Err("No images provided".to_string());
+            }
+            if images.len() != light_dirs.len() {
+                return Err(format!(
+                    "Expected {} images to match the light directions, got {}",
+                    light_dirs.len(),
+                    images.len()
+                ));
+            }
+            let size = Vector2::new(images[0].width() as usize, images[0].height() as usize);
+            if images.iter().any(|image| {
+                image.width() as usize != size[0] || image.height() as usize != size[1]
+            }) {
+                return Err("Images have different sizes".to_string());
+            }
+            let radiance_maps: Vec<RadianceMap> = images
+                .iter()
+                .map(|image| RadianceMap::from(image.to_owned()))
+                .collect();
+            let normals =
+                normal_utils::generate_normals_with_pseudo_inverse(pseudo_inverse, &radiance_maps);
+            output_utils::normals_to_image(&normals, &size, Default::default())
+        })
+        .collect()
+}
+
+/// Options for generate_albedo_with_config
+#[derive(Clone, Debug, Default)]
+pub struct AlbedoConfig {
+    /// Scale each image to the same mean brightness before averaging,
+    /// so brighter exposures don't dominate the average. This only
+    /// corrects for differences between images; uneven lighting within
+    /// the average is still left to the corner flattening passes.
+    /// Off by default.
+    pub balance_brightness: bool,
+    /// How the images are combined before flattening.
+    /// Defaults to the mean.
+    pub reduction: albedo_utils::Reduce,
+    /// Weight of a total variation denoising pass applied after
+    /// flattening (see albedo_utils::tv_denoise), or None to skip it.
+    /// None by default.
+    pub denoise_weight: Option<f32>,
+    /// Flatten each color channel by its own corner weights (see
+    /// albedo_utils::corner_weight_flatten_channels), rather than
+    /// scaling all channels by the overall brightness. Off by default.
+    pub per_channel: bool,
 }
 
 /// Attempts to generate an albedo map by averaging and
 /// flattening a slice of images.
 pub fn generate_albedo(images: &[DynamicImage]) -> Option<DynamicImage> {
-    let average_image = albedo_utils::average(images)?;
+    generate_albedo_with_config(images, &AlbedoConfig::default())
+}
+
+/// Generates a color albedo map, averaging and flattening each color
+/// channel independently. The result is RGB, or RGBA with the averaged
+/// alpha if the images have an alpha channel.
+pub fn generate_albedo_rgb(images: &[DynamicImage]) -> Option<DynamicImage> {
+    let config = AlbedoConfig {
+        per_channel: true,
+        ..Default::default()
+    };
+    let albedo = generate_albedo_with_config(images, &config)?;
+    if images[0].color().has_alpha() {
+        Some(albedo)
+    } else {
+        Some(albedo.to_rgb8().into())
+    }
+}
+
+/// Attempts to generate an albedo map by averaging and
+/// flattening a slice of images, with the given options. Pixels that
+/// are fully transparent in any image get a black albedo.
+pub fn generate_albedo_with_config(
+    images: &[DynamicImage],
+    config: &AlbedoConfig,
+) -> Option<DynamicImage> {
+    let average_image = if config.balance_brightness {
+        albedo_utils::reduce(&albedo_utils::balance_brightness(images), config.reduction)?
+    } else {
+        albedo_utils::reduce(images, config.reduction)?
+    };
     let mut flattened_average = average_image;
     for _ in 0..10 {
-        flattened_average = albedo_utils::corner_weight_flatten(&flattened_average);
+        flattened_average = if config.per_channel {
+            albedo_utils::corner_weight_flatten_channels(&flattened_average)
+        } else {
+            albedo_utils::corner_weight_flatten(&flattened_average)
+        };
+    }
+    if let Some(weight) = config.denoise_weight {
+        flattened_average = albedo_utils::tv_denoise(&flattened_average, weight);
+    }
+    // Fully transparent pixels have no albedo
+    let masks: Vec<Vec<bool>> = images.iter().filter_map(alpha_mask).collect();
+    if !masks.is_empty() {
+        let mut albedo = flattened_average.to_rgba8();
+        for (pixel, rgba) in albedo.pixels_mut().enumerate() {
+            if masks.iter().any(|mask| !mask[pixel]) {
+                rgba.0[..3].fill(0);
+            }
+        }
+        flattened_average = albedo.into();
     }
     Some(flattened_average)
 }
+
+/// Like generate_albedo, but works on horizontal strips of at most
+/// `tile_height` rows at a time, so very large images can be processed
+/// without holding full size intermediates for each step.
+pub fn generate_albedo_tiled(images: &[DynamicImage], tile_height: u32) -> Option<DynamicImage> {
+    let average_image = albedo_utils::average_tiled(images, tile_height)?;
+    Some(albedo_utils::corner_weight_flatten_tiled(
+        &average_image,
+        10,
+        tile_height,
+    ))
+}
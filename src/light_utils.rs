@@ -0,0 +1,307 @@
+use na::{Vector2, Vector3};
+
+/// Loads light directions from a CSV file.
+///
+/// Each row holds either `x,y,z` components, or `azimuth,elevation`
+/// in degrees, where azimuth is measured from the x axis towards the
+/// y axis, and elevation from the image plane towards the camera (z).
+/// Directions are normalized, blank lines and lines starting with `#`
+/// are ignored, and a non-numeric first row is treated as a header.
+pub fn load_light_directions_csv(path: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read light directions {}: {}", path, err))?;
+    parse_light_directions_csv(&contents)
+}
+
+/// Parses light directions from CSV text, as in load_light_directions_csv
+pub fn parse_light_directions_csv(contents: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let mut light_directions = Vec::<Vector3<f32>>::new();
+    let mut first_row = true;
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let is_first_row = std::mem::replace(&mut first_row, false);
+        let values: Result<Vec<f32>, _> = line
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect();
+        let values = match values {
+            // Allow a header on the first row
+            Err(_) if is_first_row => continue,
+            Err(err) => return Err(format!("Line {}: {}", line_number + 1, err)),
+            Ok(x) => x,
+        };
+        let light_direction = match values[..] {
+            [x, y, z] => Vector3::new(x, y, z),
+            [azimuth, elevation] => {
+                let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+                Vector3::new(
+                    azimuth.cos() * elevation.cos(),
+                    azimuth.sin() * elevation.cos(),
+                    elevation.sin(),
+                )
+            }
+            _ => {
+                return Err(format!(
+                    "Line {}: expected x,y,z or azimuth,elevation",
+                    line_number + 1
+                ))
+            }
+        };
+        let light_direction = light_direction
+            .try_normalize(f32::EPSILON)
+            .ok_or(format!("Line {}: light direction is zero", line_number + 1))?;
+        light_directions.push(light_direction);
+    }
+    if light_directions.len() < 3 {
+        return Err(format!(
+            "At least 3 light directions are needed, found {}",
+            light_directions.len()
+        ));
+    }
+    Ok(light_directions)
+}
+
+/// Parses light directions from a JSON array of `[x, y, z]` arrays,
+/// such as `[[0, 0, 1], [0.5, 0, 1], [0, 0.5, 1]]`. Directions are
+/// normalized, and at least 3 are needed.
+pub fn parse_light_directions_json(contents: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let inner = contents
+        .trim()
+        .strip_prefix('[')
+        .and_then(|inner| inner.strip_suffix(']'))
+        .ok_or("Expected a JSON array of light directions".to_string())?;
+    let mut light_directions = Vec::<Vector3<f32>>::new();
+    let mut rest = inner.trim();
+    while !rest.is_empty() {
+        let entry_number = light_directions.len() + 1;
+        let end = rest
+            .find(']')
+            .ok_or(format!("Entry {}: expected [x, y, z]", entry_number))?;
+        let entry = rest[..end]
+            .trim()
+            .strip_prefix('[')
+            .ok_or(format!("Entry {}: expected [x, y, z]", entry_number))?;
+        let values: Vec<f32> = entry
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("Entry {}: {}", entry_number, err))?;
+        let light_direction = match values[..] {
+            [x, y, z] => Vector3::new(x, y, z),
+            _ => {
+                return Err(format!(
+                    "Entry {}: expected 3 numbers, found {}",
+                    entry_number,
+                    values.len()
+                ))
+            }
+        };
+        let light_direction = light_direction
+            .try_normalize(f32::EPSILON)
+            .ok_or(format!("Entry {}: light direction is zero", entry_number))?;
+        light_directions.push(light_direction);
+        rest = rest[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+    if light_directions.len() < 3 {
+        return Err(format!(
+            "At least 3 light directions are needed, found {}",
+            light_directions.len()
+        ));
+    }
+    Ok(light_directions)
+}
+
+/// Loads light directions from a JSON array of `[x, y, z]` arrays, or
+/// otherwise from CSV (see parse_light_directions_csv)
+pub fn load_light_directions(path: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read light directions {}: {}", path, err))?;
+    if contents.trim_start().starts_with('[') {
+        parse_light_directions_json(&contents)
+    } else {
+        parse_light_directions_csv(&contents)
+    }
+}
+
+/// Loads light directions from a YAML calibration file, such as the
+/// `lights.yml` shipped with many photometric stereo datasets.
+///
+/// Only a YAML list of `[x, y, z]` flow sequences is understood, which
+/// keeps this free of a YAML dependency. The list may sit under a
+/// single key, like `lights:`. Directions are normalized on load.
+pub fn load_light_directions_yaml(path: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read light directions {}: {}", path, err))?;
+    parse_light_directions_yaml(&contents)
+}
+
+/// Parses light directions from YAML text, as in load_light_directions_yaml
+pub fn parse_light_directions_yaml(contents: &str) -> Result<Vec<Vector3<f32>>, String> {
+    let mut light_directions = Vec::<Vector3<f32>>::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        }
+        .trim();
+        // Skip blank lines, document markers, and the key holding the list
+        if line.is_empty() || line == "---" || (line.ends_with(':') && light_directions.is_empty())
+        {
+            continue;
+        }
+        let entry = line
+            .strip_prefix('-')
+            .map(str::trim)
+            .and_then(|entry| entry.strip_prefix('['))
+            .and_then(|entry| entry.strip_suffix(']'))
+            .ok_or(format!("Line {}: expected - [x, y, z]", line_number + 1))?;
+        let values: Vec<f32> = entry
+            .split(',')
+            .map(|value| value.trim().parse::<f32>())
+            .collect::<Result<_, _>>()
+            .map_err(|err| format!("Line {}: {}", line_number + 1, err))?;
+        let light_direction = match values[..] {
+            [x, y, z] => Vector3::new(x, y, z),
+            _ => return Err(format!("Line {}: expected - [x, y, z]", line_number + 1)),
+        };
+        let light_direction = light_direction
+            .try_normalize(f32::EPSILON)
+            .ok_or(format!("Line {}: light direction is zero", line_number + 1))?;
+        light_directions.push(light_direction);
+    }
+    if light_directions.len() < 3 {
+        return Err(format!(
+            "At least 3 light directions are needed, found {}",
+            light_directions.len()
+        ));
+    }
+    Ok(light_directions)
+}
+
+/// Finds the angle in degrees between each estimated light direction
+/// and the matching known direction, for checking the accuracy of
+/// light estimation against a calibrated rig.
+pub fn light_angular_errors(
+    estimated: &[Vector3<f32>],
+    known: &[Vector3<f32>],
+) -> Result<Vec<f32>, String> {
+    if estimated.len() != known.len() {
+        return Err(format!(
+            "{} estimated light directions, but {} known ones",
+            estimated.len(),
+            known.len()
+        ));
+    }
+    Ok(estimated
+        .iter()
+        .zip(known)
+        .map(|(estimated, known)| estimated.angle(known).to_degrees())
+        .collect())
+}
+
+/// Groups light directions that are within `angular_threshold` degrees
+/// of each other, to find captures that repeat the same lighting.
+/// Closeness is chained, so a direction joins a group if it is close to
+/// any member. Each group lists indices into `directions` in order, and
+/// the groups are ordered by their first index.
+pub fn cluster_lights(directions: &[Vector3<f32>], angular_threshold: f32) -> Vec<Vec<usize>> {
+    // The group each direction belongs to
+    let mut group_of: Vec<usize> = (0..directions.len()).collect();
+    for i in 0..directions.len() {
+        for j in 0..i {
+            if directions[i].angle(&directions[j]).to_degrees() > angular_threshold {
+                continue;
+            }
+            // Merge i's group into j's
+            let (from, to) = (group_of[i], group_of[j]);
+            for group in group_of.iter_mut() {
+                if *group == from {
+                    *group = to;
+                }
+            }
+        }
+    }
+    let mut clusters = Vec::<Vec<usize>>::new();
+    let mut cluster_of_group = std::collections::HashMap::<usize, usize>::new();
+    for (i, group) in group_of.into_iter().enumerate() {
+        let cluster = *cluster_of_group.entry(group).or_insert_with(|| {
+            clusters.push(Vec::new());
+            clusters.len() - 1
+        });
+        clusters[cluster].push(i);
+    }
+    clusters
+}
+
+/// Picks `k` of the light directions that give the best conditioned
+/// solve, so captures that add little can be left out.
+///
+/// Lights are chosen greedily, each maximizing the smallest singular
+/// value of the matrix of chosen directions (or, with fewer than three
+/// chosen, the smallest of the nonzero ones). This favors directions
+/// spread apart over near duplicates. The result holds indices into
+/// `directions`, in the order they were chosen.
+pub fn select_lights(directions: &[Vector3<f32>], k: usize) -> Vec<usize> {
+    let score = |selection: &[usize]| {
+        let matrix = na::DMatrix::from_fn(selection.len(), 3, |row, axis| {
+            directions[selection[row]].normalize()[axis]
+        });
+        let mut singular_values: Vec<f32> = matrix.singular_values().iter().copied().collect();
+        singular_values.sort_by(|a, b| b.total_cmp(a));
+        singular_values[selection.len().min(3) - 1]
+    };
+    let mut selected = Vec::<usize>::new();
+    while selected.len() < k.min(directions.len()) {
+        let best = (0..directions.len())
+            .filter(|i| !selected.contains(i))
+            .map(|i| {
+                let mut candidate = selected.clone();
+                candidate.push(i);
+                (i, score(&candidate))
+            })
+            .fold(None, |best: Option<(usize, f32)>, (i, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((i, score)),
+            });
+        match best {
+            Some((i, _)) => selected.push(i),
+            None => break,
+        }
+    }
+    selected
+}
+
+/// Finds a bright calibration sphere in an image, returning its center
+/// (x, y) and radius in pixels. Pixels brighter than halfway between
+/// the darkest and brightest are taken as the sphere, whose center is
+/// their centroid, and whose radius gives a circle of the same area.
+/// This assumes the sphere is evenly lit against a darker background.
+/// Returns None if the image has no contrast.
+pub fn detect_sphere(image: &image::DynamicImage) -> Option<(Vector2<f32>, f32)> {
+    let luma = image.to_luma32f();
+    let (low, high) = luma
+        .pixels()
+        .fold((f32::MAX, f32::MIN), |(low, high), pixel| {
+            (low.min(pixel.0[0]), high.max(pixel.0[0]))
+        });
+    if high - low <= f32::EPSILON {
+        return None;
+    }
+    let threshold = (low + high) / 2.0;
+    let mut total = Vector2::<f64>::zeros();
+    let mut count = 0usize;
+    for (x, y, pixel) in luma.enumerate_pixels() {
+        if pixel.0[0] > threshold {
+            // Pixel centers are half a pixel in from their corners
+            total += Vector2::new(x as f64 + 0.5, y as f64 + 0.5);
+            count += 1;
+        }
+    }
+    let center = (total / count as f64).cast::<f32>();
+    let radius = (count as f32 / std::f32::consts::PI).sqrt();
+    Some((center, radius))
+}
@@ -1,49 +1,162 @@
-use image::{DynamicImage, ImageReader};
+use clap::error::ContextKind;
+use clap::{CommandFactory, Parser};
+use image::DynamicImage;
 use nalgebra::Vector2;
 use normals_from_shading::*;
+use std::process::ExitCode;
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
+/// Draws a progress bar for a phase of the solve on stderr, keeping
+/// stdout for the results
+fn show_progress(event: ProgressEvent) {
+    const WIDTH: usize = 20;
+    // Light estimation and the normal solve alternate in each
+    // refinement iteration, so they share one bar
+    let label = match event.phase {
+        ProgressPhase::LightEstimation => return,
+        ProgressPhase::NormalSolve => "Refinement",
+        ProgressPhase::Flatten => "Flatten",
+    };
+    let filled = (event.fraction.clamp(0.0, 1.0) * WIDTH as f32).round() as usize;
+    eprint!(
+        "\r{:<10} [{}{}] {:>3.0}%",
+        label,
+        "#".repeat(filled),
+        " ".repeat(WIDTH - filled),
+        event.fraction * 100.0
+    );
+    if event.fraction >= 1.0 {
+        eprintln!();
+    }
+}
+
+/// Generates a normal map and albedo from photos of a subject, each
+/// lit from a different direction
+#[derive(Parser)]
+struct Args {
+    /// Where to write the albedo
+    #[arg(long, value_name = "PATH", default_value = "albedo.png")]
+    albedo_out: String,
+    /// Where to write the normal map
+    #[arg(long, value_name = "PATH", default_value = "normal_map.png")]
+    normal_out: String,
+    /// Number of refinement iterations
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = NormalMapConfig::default().refinement_iterations
+    )]
+    iterations: usize,
+    /// Number of threads [default: one per core]
+    #[arg(long, value_name = "COUNT")]
+    threads: Option<usize>,
+    /// Drop color from each image as it's loaded
+    #[arg(long)]
+    grayscale: bool,
+    /// Decode sRGB images to linear brightness
+    #[arg(long)]
+    linearize: bool,
+    /// Write the normal map with +Y pointing up
+    #[arg(long)]
+    opengl: bool,
+    /// Photos of the subject
+    #[arg(value_name = "IMAGE", required = true)]
+    images: Vec<String>,
+}
+
+impl Args {
+    fn config(&self) -> NormalMapConfig {
+        let mut config = NormalMapConfig {
+            refinement_iterations: self.iterations,
+            threads: self.threads,
+            linearize: self.linearize,
+            ..Default::default()
+        };
+        if self.opengl {
+            config.image_options.convention = output_utils::NormalConvention::OpenGl;
+        }
+        config
+    }
+}
+
+/// Loads the images, and writes the albedo and normal map, returning
+/// a message for anything that goes wrong
+fn run(args: &Args) -> Result<(), String> {
     let mut images = Vec::<DynamicImage>::new();
 
     // Load images
-    for path in &args[1..] {
-        let image = ImageReader::open(path)
-            .unwrap_or_else(|_| panic!("Could not open image: {}", path))
-            .decode()
-            .unwrap_or_else(|_| panic!("Could not decode image: {}", path));
-        images.push(image);
+    for path in &args.images {
+        let image = if args.grayscale {
+            radiance_map::load_grayscale(path)
+        } else {
+            radiance_map::load_oriented(path)
+        };
+        images.push(image.map_err(|err| format!("Could not load image {}: {}", path, err))?);
     }
 
     // Validate images
-    if images.is_empty() {
-        println!("No images provided");
-        return;
-    }
-
     let get_size =
         |image: &DynamicImage| Vector2::new(image.width() as usize, image.height() as usize);
     let size = get_size(&images[0]);
-    for image in &images {
+    for (path, image) in args.images.iter().zip(&images) {
         if get_size(image) != size {
-            println!("Images have different sizes");
-            println!("{}, {}", size, get_size(image));
-            return;
+            return Err(format!(
+                "Images have different sizes: {} is {}x{}, but {} is {}x{}",
+                args.images[0],
+                size[0],
+                size[1],
+                path,
+                image.width(),
+                image.height()
+            ));
         }
     }
 
     // Generate albedo
-    let albedo = generate_albedo(&images).expect("Error generating albedo");
+    let albedo = generate_albedo(&images).ok_or("Error generating albedo")?;
     albedo
-        .save_with_format("albedo.png", image::ImageFormat::Png)
-        .expect("Error saving albedo");
+        .save(&args.albedo_out)
+        .map_err(|err| format!("Error saving albedo to {}: {}", args.albedo_out, err))?;
 
     // Generate normal map
-    let normal_map = match generate_normal_map(&images) {
-        Err(err) => return println!("{}", err),
-        Ok(x) => x,
+    let result = generate_normal_map_result_with_progress(&images, &args.config(), show_progress)?;
+    for light_direction in &result.light_directions {
+        println!("Est light direction: {}", light_direction);
+    }
+    let status = if result.converged {
+        "converged"
+    } else {
+        "ran every iteration"
     };
-    normal_map
-        .save_with_format("normal_map.png", image::ImageFormat::Png)
-        .expect("Error writing normal map");
+    println!(
+        "Refined for {} iterations ({}), quality {:.3}",
+        result.iterations(),
+        status,
+        result.quality
+    );
+    result
+        .normal_map
+        .save(&args.normal_out)
+        .map_err(|err| format!("Error writing normal map to {}: {}", args.normal_out, err))
+}
+
+fn main() -> ExitCode {
+    // Argument errors print the usage and exit with status 2. clap
+    // leaves the usage out of some errors, such as a missing or
+    // unparsable value, so add it there.
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(err) if err.use_stderr() && err.get(ContextKind::Usage).is_none() => {
+            let _ = err.print();
+            eprintln!("\n{}", Args::command().render_usage());
+            return ExitCode::from(2);
+        }
+        Err(err) => err.exit(),
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
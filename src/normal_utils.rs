@@ -1,4 +1,7 @@
-use na::{DMatrix, Matrix3, Rotation3, Vector2, Vector3};
+use image::DynamicImage;
+use na::{DMatrix, DVector, Matrix3, Matrix3xX, Rotation3, Vector2, Vector3};
+use rayon::prelude::*;
+use std::io::{self, Read, Write};
 
 use crate::radiance_map::*;
 
@@ -15,6 +18,163 @@ pub fn least_squares(a: &NormalMatrix, b: &RadianceMatrix) -> Option<Vector3<f32
     Some(inv_ata * atb)
 }
 
+/// Like least_squares, but each row of the system counts in proportion
+/// to its weight, solving the weighted normal equations AᵀWAx = AᵀWb.
+/// This will return None for an underconstrained system.
+pub fn least_squares_weighted(
+    a: &NormalMatrix,
+    b: &RadianceMatrix,
+    weights: &DVector<f32>,
+) -> Option<Vector3<f32>> {
+    let weighted_transpose = a.transpose() * DMatrix::from_diagonal(weights);
+    let atwa = &weighted_transpose * a;
+    let atwb = &weighted_transpose * b;
+
+    let inv_atwa = atwa.try_inverse()?;
+    Some(inv_atwa * atwb)
+}
+
+/// Smallest weight radiance_confidence gives, so observations at the
+/// extremes still help constrain the solve
+pub const MIN_RADIANCE_CONFIDENCE: f32 = 0.01;
+
+/// How reliable an observation of the given brightness is, a bell
+/// curve peaking at 1 for mid-grey (0.5), and falling towards
+/// MIN_RADIANCE_CONFIDENCE as it nears shadow (0) or saturation (1),
+/// where noise and clipping dominate.
+pub fn radiance_confidence(radiance: f32) -> f32 {
+    (4.0 * radiance * (1.0 - radiance)).max(MIN_RADIANCE_CONFIDENCE)
+}
+
+/// Largest condition number (ratio of largest to smallest singular
+/// value) least_squares_svd accepts before treating the system as
+/// underconstrained
+pub const MAX_CONDITION_NUMBER: f32 = 1e5;
+
+/// Like least_squares, but solves with the singular value
+/// decomposition of A, rather than inverting AᵀA, which squares the
+/// condition number and loses precision when the rows of A (such as
+/// light directions) are nearly coplanar. Like least_squares, this
+/// returns None when A has fewer than 3 rows, and also when A has rank
+/// less than 3 once singular values below 1 / MAX_CONDITION_NUMBER of
+/// the largest are discarded.
+pub fn least_squares_svd(a: &NormalMatrix, b: &RadianceMatrix) -> Option<Vector3<f32>> {
+    // An n x 3 matrix only has n singular values, so with fewer than 3
+    // rows the rank check below can't see the missing ones
+    if a.nrows() < 3 {
+        return None;
+    }
+    let svd = a.clone().svd(true, true);
+    let largest = svd.singular_values.max();
+    if largest <= 0.0 || svd.rank(largest / MAX_CONDITION_NUMBER) < 3 {
+        return None;
+    }
+    let solution = svd.solve(b, 0.0).ok()?;
+    Some(Vector3::new(solution[0], solution[1], solution[2]))
+}
+
+/// How each pixel's least squares system is solved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LeastSquaresSolver {
+    /// Invert the normal equations (see least_squares)
+    #[default]
+    NormalEquations,
+    /// Use the singular value decomposition (see least_squares_svd),
+    /// which is more accurate for nearly coplanar lights
+    Svd,
+}
+
+impl LeastSquaresSolver {
+    /// Solves Ax = b with this solver
+    pub fn solve(&self, a: &NormalMatrix, b: &RadianceMatrix) -> Option<Vector3<f32>> {
+        match self {
+            LeastSquaresSolver::NormalEquations => least_squares(a, b),
+            LeastSquaresSolver::Svd => least_squares_svd(a, b),
+        }
+    }
+
+    /// Solves Ax = b with this solver, weighting each row (see
+    /// least_squares_weighted). The SVD solver scales each row by the
+    /// square root of its weight, which minimizes the same error.
+    pub fn solve_weighted(
+        &self,
+        a: &NormalMatrix,
+        b: &RadianceMatrix,
+        weights: &DVector<f32>,
+    ) -> Option<Vector3<f32>> {
+        match self {
+            LeastSquaresSolver::NormalEquations => least_squares_weighted(a, b, weights),
+            LeastSquaresSolver::Svd => {
+                let scales = weights.map(f32::sqrt);
+                let mut a = a.clone();
+                for (mut row, scale) in a.row_iter_mut().zip(scales.iter()) {
+                    row *= *scale;
+                }
+                least_squares_svd(&a, &b.component_mul(&scales))
+            }
+        }
+    }
+}
+
+/// Robust least squares solution to Ax = b, ignoring rows that don't
+/// fit, such as a specular highlight or cast shadow in one image.
+///
+/// Each iteration solves a random set of three rows exactly, and counts
+/// the rows it fits within `inlier_threshold` (its inliers). The result
+/// is the least squares solution of the largest set of inliers found.
+/// The random sampling is seeded, so the result is repeatable. If no
+/// sample finds three inliers, this falls back to least_squares.
+/// This will return None for an underconstrained system.
+pub fn least_squares_ransac(
+    a: &NormalMatrix,
+    b: &RadianceMatrix,
+    iterations: usize,
+    inlier_threshold: f32,
+) -> Option<Vector3<f32>> {
+    let rows = a.nrows();
+    if rows < 3 {
+        return None;
+    }
+    let mut state = 0x2545F4914F6CDD1Du64;
+    let mut random_row = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((state >> 33) % rows as u64) as usize
+    };
+
+    let mut best_inliers = Vec::<usize>::new();
+    for _ in 0..iterations {
+        let sample = [random_row(), random_row(), random_row()];
+        if sample[0] == sample[1] || sample[0] == sample[2] || sample[1] == sample[2] {
+            continue;
+        }
+        let Some(x) = least_squares(&a.select_rows(&sample), &b.select_rows(&sample)) else {
+            continue;
+        };
+        let inliers: Vec<usize> = (0..rows)
+            .filter(|row| ((a.row(*row) * x)[0] - b[*row]).abs() <= inlier_threshold)
+            .collect();
+        if inliers.len() > best_inliers.len() {
+            best_inliers = inliers;
+        }
+    }
+    if best_inliers.len() < 3 {
+        return least_squares(a, b);
+    }
+    least_squares(&a.select_rows(&best_inliers), &b.select_rows(&best_inliers))
+}
+
+/// Finds the pseudo-inverse (AᵀA)⁻¹Aᵀ used by least squares.
+/// Precomputing it lets many systems sharing the same A be solved
+/// with a single multiplication each.
+/// This will return None for an underconstrained system.
+pub fn pseudo_inverse(a: &NormalMatrix) -> Option<Matrix3xX<f32>> {
+    let a_transpose = a.transpose();
+    let inv_ata = (&a_transpose * a).try_inverse()?;
+    Some(inv_ata * a_transpose)
+}
+
 /// Estimating a lighting direction by finding the least squares solution
 /// for (light_direction) of (normals)(light_directions) = (brightness_values)
 /// This is based on phong diffuse shading.
@@ -22,17 +182,93 @@ pub fn least_squares(a: &NormalMatrix, b: &RadianceMatrix) -> Option<Vector3<f32
 /// The normal matrix must be an n x 3 matrix where n is the pixel count, and
 /// each row holds the xyz values of the normal. The radiance vector is an
 /// n x 1 matrix holding brightness data for each pixel.
+///
+/// Returns an error if the normals are too alike to constrain the
+/// light, or the solution is zero, as for an entirely black image.
 pub fn generate_lighting_direction(
     normal_matrix: &NormalMatrix,
     radiance_vector: &RadianceMatrix,
-) -> Vector3<f32> {
+) -> Result<Vector3<f32>, String> {
     // least squares solution for normal * light_direction = radiance;
-    let light_direction = least_squares(normal_matrix, radiance_vector)
-        .expect("Could not find least squares for lighting direction")
-        .normalize();
+    least_squares(normal_matrix, radiance_vector)
+        .ok_or_else(|| "The normals are too alike to find a lighting direction".to_string())?
+        .try_normalize(0.0)
+        .ok_or_else(|| "The image is too dark to find a lighting direction".to_string())
+}
+
+/// Like generate_lighting_direction, but leaves out pixels whose
+/// radiance is below `low` (shadowed) or above `high` (saturated or
+/// specular), since they don't follow diffuse shading. If fewer than
+/// three pixels are within the band, which isn't enough to constrain
+/// the light, every pixel is used instead.
+pub fn generate_lighting_direction_in_band(
+    normal_matrix: &NormalMatrix,
+    radiance_vector: &RadianceMatrix,
+    low: f32,
+    high: f32,
+) -> Result<Vector3<f32>, String> {
+    let pixels: Vec<usize> = (0..radiance_vector.nrows())
+        .filter(|pixel| (low..=high).contains(&radiance_vector[*pixel]))
+        .collect();
+    if pixels.len() < 3 {
+        return generate_lighting_direction(normal_matrix, radiance_vector);
+    }
+    generate_lighting_direction(
+        &normal_matrix.select_rows(&pixels),
+        &radiance_vector.select_rows(&pixels),
+    )
+}
 
-    // return as vec3
-    Vector3::<f32>::from_column_slice(light_direction.as_slice())
+/// Statistics about a per-pixel normal solve
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SolveStats {
+    /// Number of pixels in the solve
+    pub pixel_count: usize,
+    /// Number of pixels that couldn't be solved,
+    /// and were given a forward facing normal instead
+    pub fallback_count: usize,
+    /// The pixels that couldn't be solved, in order
+    pub fallback_pixels: Vec<usize>,
+}
+
+impl SolveStats {
+    /// Fraction of pixels that couldn't be solved
+    pub fn fallback_fraction(&self) -> f32 {
+        if self.pixel_count == 0 {
+            return 0.0;
+        }
+        self.fallback_count as f32 / self.pixel_count as f32
+    }
+}
+
+/// Options for generate_normals_with_options
+#[derive(Clone, Debug)]
+pub struct SolveOptions {
+    /// Observations at or above this brightness are treated as
+    /// saturated or specular, and left out of that pixel's solve.
+    /// Defaults to 0.95.
+    pub specular_threshold: f32,
+    /// Observations below this brightness are treated as shadowed, and
+    /// left out of that pixel's solve. Defaults to 0.05.
+    pub shadow_threshold: f32,
+    /// How each pixel's least squares system is solved. Defaults to
+    /// the normal equations.
+    pub solver: LeastSquaresSolver,
+    /// Weight each observation by how reliable its brightness is (see
+    /// radiance_confidence), rather than counting them all equally.
+    /// On by default.
+    pub confidence_weighting: bool,
+}
+
+impl Default for SolveOptions {
+    fn default() -> Self {
+        Self {
+            specular_threshold: 0.95,
+            shadow_threshold: 0.05,
+            solver: LeastSquaresSolver::NormalEquations,
+            confidence_weighting: true,
+        }
+    }
 }
 
 /// Using a set of radiance maps, including brightness and
@@ -40,35 +276,333 @@ pub fn generate_lighting_direction(
 /// of each pixel by finding the least squares solution
 /// for (normals) of (light_directions)(normals) = (brightness_values).
 /// This is based on phong diffuse shading.
+///
+/// Pixels are solved in parallel on the current rayon thread pool.
+/// Pixels that can't be solved face forwards, as do pixels masked out
+/// of any radiance map (see RadianceMap::mask).
 pub fn generate_normals(radiance_maps: &[RadianceMap]) -> NormalMatrix {
+    generate_normals_with_stats(radiance_maps).0
+}
+
+/// Like generate_normals, but also reports how many pixels couldn't
+/// be solved.
+pub fn generate_normals_with_stats(radiance_maps: &[RadianceMap]) -> (NormalMatrix, SolveStats) {
+    generate_normals_with_options(radiance_maps, &SolveOptions::default())
+}
+
+/// Finds which radiance maps give a valid observation of a pixel
+/// under the given options. Each pixel can have a different set.
+pub fn valid_observations(
+    radiance_maps: &[RadianceMap],
+    pixel: usize,
+    options: &SolveOptions,
+) -> Vec<usize> {
+    (0..radiance_maps.len())
+        .filter(|i| {
+            let radiance = radiance_maps[*i].radiance[pixel];
+            radiance >= options.shadow_threshold && radiance < options.specular_threshold
+        })
+        .collect()
+}
+
+/// Like generate_normals_with_stats, but each pixel is solved from
+/// only its own valid observations (see valid_observations), so a
+/// pixel that is saturated in some images still uses all the others.
+/// Pixels with fewer than three valid observations can't be solved.
+pub fn generate_normals_with_options(
+    radiance_maps: &[RadianceMap],
+    options: &SolveOptions,
+) -> (NormalMatrix, SolveStats) {
+    let pixel_count = radiance_maps.first().map_or(0, |map| map.size.product());
     // perform a least squares for each pixel
-    let normals: Vec<f32> = (0..radiance_maps[0].size.product())
-        .flat_map(|pixel| {
+    let normals: Vec<Option<Vector3<f32>>> = (0..pixel_count)
+        .into_par_iter()
+        .map(|pixel| {
+            if radiance_maps.iter().any(|map| map.is_masked(pixel)) {
+                return Some(Vector3::z());
+            }
+            let observations = valid_observations(radiance_maps, pixel, options);
+            if observations.len() < 3 {
+                return None;
+            }
             let mut light_directions: Vec<f32> = Vec::new();
             let mut radiances: Vec<f32> = Vec::new();
-            for radiance_map in radiance_maps {
+            for i in observations {
+                let radiance_map = &radiance_maps[i];
                 light_directions.extend_from_slice(radiance_map.lighting_direction.as_slice());
                 radiances.push(radiance_map.radiance[pixel]);
             }
             let light_directions = NormalMatrix::from_row_slice(&light_directions);
             let radiances = RadianceMatrix::from_row_slice(&radiances);
-            let least_squares_normal = least_squares(&light_directions, &radiances);
-            Vec::from(
-                least_squares_normal
-                    .expect("Could not find least squares for normal map")
-                    .normalize()
-                    .as_slice(),
-            )
+            let solution = if options.confidence_weighting {
+                let weights = radiances.map(radiance_confidence);
+                options
+                    .solver
+                    .solve_weighted(&light_directions, &radiances, &weights)?
+            } else {
+                options.solver.solve(&light_directions, &radiances)?
+            };
+            solution.try_normalize(0.0)
+        })
+        .collect();
+
+    let stats = SolveStats {
+        pixel_count: normals.len(),
+        fallback_count: normals.iter().filter(|normal| normal.is_none()).count(),
+        fallback_pixels: (0..normals.len())
+            .filter(|pixel| normals[*pixel].is_none())
+            .collect(),
+    };
+    let mut normal_matrix = Vec::<f32>::new();
+    for normal in normals {
+        normal_matrix.extend_from_slice(normal.unwrap_or(Vector3::z()).as_slice());
+    }
+    (NormalMatrix::from_row_slice(&normal_matrix), stats)
+}
+
+/// Solves normals from color images, treating the red, green, and
+/// blue channels of each image (`channel_maps[i]`, see
+/// radiance_map::color_channels) as separate observations of the same
+/// normal under the image's light, each with its own albedo.
+///
+/// Each pixel's normal is the direction that best fits all three
+/// channels at once, which weights the channels by how strongly they
+/// respond, rather than by fixed greyscale weights. An image is a valid
+/// observation of a pixel when its brightest channel is at least
+/// `options.shadow_threshold` and below `options.specular_threshold`.
+pub fn generate_normals_color(
+    channel_maps: &[[RadianceMap; 3]],
+    options: &SolveOptions,
+) -> (NormalMatrix, SolveStats) {
+    let pixel_count = channel_maps
+        .first()
+        .map_or(0, |maps| maps[0].size.product());
+    let normals: Vec<Option<Vector3<f32>>> = (0..pixel_count)
+        .into_par_iter()
+        .map(|pixel| {
+            if channel_maps.iter().any(|maps| maps[0].is_masked(pixel)) {
+                return Some(Vector3::z());
+            }
+            let observations: Vec<&[RadianceMap; 3]> = channel_maps
+                .iter()
+                .filter(|maps| {
+                    let brightest = maps
+                        .iter()
+                        .map(|map| map.radiance[pixel])
+                        .fold(0.0, f32::max);
+                    brightest >= options.shadow_threshold && brightest < options.specular_threshold
+                })
+                .collect();
+            if observations.len() < 3 {
+                return None;
+            }
+            let lights = DMatrix::from_fn(observations.len(), 3, |row, axis| {
+                observations[row][0].lighting_direction[axis]
+            });
+            let radiances = DMatrix::from_fn(observations.len(), 3, |row, channel| {
+                observations[row][channel].radiance[pixel]
+            });
+            // The normal n maximizes the fit of the best albedo for each
+            // channel, nᵀ(LᵀIIᵀL)n / nᵀ(LᵀL)n, a generalized eigenproblem
+            let gram = lights.transpose() * &lights;
+            let correlation = lights.transpose() * &radiances;
+            let cholesky = na::Cholesky::new(Matrix3::from_iterator(gram.iter().copied()))?;
+            let lower_inverse = cholesky.l().try_inverse()?;
+            let correlation = Matrix3::from_iterator(correlation.iter().copied());
+            let whitened =
+                lower_inverse * correlation * correlation.transpose() * lower_inverse.transpose();
+            let eigen = na::SymmetricEigen::new(whitened);
+            let largest = eigen.eigenvalues.imax();
+            let normal = lower_inverse.transpose() * eigen.eigenvectors.column(largest);
+            // Face the normal so the albedos are positive
+            let sign = normal.dot(&correlation.column_sum()).signum();
+            (normal * sign).try_normalize(0.0)
+        })
+        .collect();
+
+    let stats = SolveStats {
+        pixel_count: normals.len(),
+        fallback_count: normals.iter().filter(|normal| normal.is_none()).count(),
+        fallback_pixels: (0..normals.len())
+            .filter(|pixel| normals[*pixel].is_none())
+            .collect(),
+    };
+    let mut normal_matrix = Vec::<f32>::new();
+    for normal in normals {
+        normal_matrix.extend_from_slice(normal.unwrap_or(Vector3::z()).as_slice());
+    }
+    (NormalMatrix::from_row_slice(&normal_matrix), stats)
+}
+
+/// Finds the length of each pixel's least squares solution before it
+/// is normalized into a normal. This grows with albedo and with how
+/// strongly the pixel responds to the lights, so it shows where the
+/// diffuse fit is well supported. Unsolvable and masked out pixels
+/// are given zero.
+pub fn least_squares_magnitudes(
+    radiance_maps: &[RadianceMap],
+    options: &SolveOptions,
+) -> RadianceMatrix {
+    let magnitudes: Vec<f32> = (0..radiance_maps[0].size.product())
+        .into_par_iter()
+        .map(|pixel| {
+            if radiance_maps.iter().any(|map| map.is_masked(pixel)) {
+                return 0.0;
+            }
+            let observations = valid_observations(radiance_maps, pixel, options);
+            let light_directions = NormalMatrix::from_fn(observations.len(), |row, axis| {
+                radiance_maps[observations[row]].lighting_direction[axis]
+            });
+            let radiances = RadianceMatrix::from_iterator(
+                observations.len(),
+                observations
+                    .iter()
+                    .map(|i| radiance_maps[*i].radiance[pixel]),
+            );
+            options
+                .solver
+                .solve(&light_directions, &radiances)
+                .map_or(0.0, |solution| solution.norm())
         })
         .collect();
+    RadianceMatrix::from_vec(magnitudes)
+}
 
+/// Estimates the normal direction of each pixel for radiance maps lit
+/// by known lights, given the pseudo-inverse of the matrix of their
+/// lighting directions (in the same order as the radiance maps).
+/// Pixels with no brightness at all are given a forward normal.
+pub fn generate_normals_with_pseudo_inverse(
+    pseudo_inverse: &Matrix3xX<f32>,
+    radiance_maps: &[RadianceMap],
+) -> NormalMatrix {
+    let normals: Vec<f32> = (0..radiance_maps[0].size.product())
+        .flat_map(|pixel| {
+            let radiances = RadianceMatrix::from_iterator(
+                radiance_maps.len(),
+                radiance_maps
+                    .iter()
+                    .map(|radiance_map| radiance_map.radiance[pixel]),
+            );
+            let normal = (pseudo_inverse * radiances)
+                .try_normalize(0.0)
+                .unwrap_or(Vector3::z());
+            Vec::from(normal.as_slice())
+        })
+        .collect();
+
+    NormalMatrix::from_row_slice(&normals)
+}
+
+/// Approximates normals from the brightness gradient of a single
+/// radiance map, treating brighter pixels as higher. This needs no
+/// lighting information, but is only a rough guess at the shape.
+/// `strength` scales how steep the brightness makes the surface.
+pub fn normals_from_brightness(
+    radiance: &RadianceMatrix,
+    size: &Vector2<usize>,
+    strength: f32,
+) -> NormalMatrix {
+    let brightness = |x: usize, y: usize| radiance[y * size[0] + x];
+    let mut normals = Vec::<f32>::new();
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            // Central differences, clamped to the image
+            let (left, right) = (x.saturating_sub(1), (x + 1).min(size[0] - 1));
+            let (up, down) = (y.saturating_sub(1), (y + 1).min(size[1] - 1));
+            let dx = (brightness(right, y) - brightness(left, y)) / (right - left).max(1) as f32;
+            let dy = (brightness(x, down) - brightness(x, up)) / (down - up).max(1) as f32;
+            let normal = Vector3::new(-dx * strength, -dy * strength, 1.0).normalize();
+            normals.extend_from_slice(normal.as_slice());
+        }
+    }
     NormalMatrix::from_row_slice(&normals)
 }
 
-// Rotates normals so their average points upwards
+/// Fuses aligned normal fields of the same object, such as from
+/// different views, weighting each normal by its confidence. Pixels
+/// with no confidence in any field face forwards.
+pub fn merge_normal_maps(maps: &[(NormalMatrix, RadianceMatrix)]) -> NormalMatrix {
+    let pixel_count = maps.first().map_or(0, |(normals, _)| normals.nrows());
+    let mut merged = NormalMatrix::zeros(pixel_count);
+    for (normals, confidence) in maps {
+        for i in 0..pixel_count {
+            let weighted = normals.row(i) * confidence[i];
+            merged.set_row(i, &(merged.row(i) + weighted));
+        }
+    }
+    for mut normal in merged.row_iter_mut() {
+        if normal.try_normalize_mut(0.0).is_none() {
+            normal.copy_from(&Vector3::z().transpose());
+        }
+    }
+    merged
+}
+
+/// Finds the mean angle in degrees between matching normals
+/// of two normal matrices.
+pub fn mean_angle_between(a: &NormalMatrix, b: &NormalMatrix) -> f32 {
+    let total: f32 = a
+        .row_iter()
+        .zip(b.row_iter())
+        .map(|(a, b)| a.dot(&b).clamp(-1.0, 1.0).acos().to_degrees())
+        .sum();
+    total / a.nrows().max(1) as f32
+}
+
+/// Normals of a shallow dome covering the image, tilting outwards from
+/// the center. This is the starting guess that the light directions
+/// and normals are refined from.
+pub fn dome_normals(size: &Vector2<usize>) -> NormalMatrix {
+    let mut normals = Vec::<f32>::new();
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            normals.extend_from_slice(
+                Vector3::new(
+                    x as f32 - size[0] as f32 / 2.0,
+                    y as f32 - size[1] as f32 / 2.0,
+                    size[0].max(size[1]) as f32,
+                )
+                .normalize()
+                .as_slice(),
+            );
+        }
+    }
+    NormalMatrix::from_row_slice(&normals)
+}
+
+// Rotates normals so their average points upwards.
+// If opposing normals cancel out, the average has no direction,
+// so the normals are returned unchanged.
 pub fn reorient_normals(normals: &NormalMatrix) -> NormalMatrix {
-    let average_normal_raw = normals.row_mean().normalize();
-    let average_normal = Vector3::from_row_slice(average_normal_raw.as_slice());
+    let average_normal_raw = normals.row_mean();
+    rotate_to_camera(
+        normals,
+        Vector3::from_row_slice(average_normal_raw.as_slice()),
+    )
+}
+
+/// Like reorient_normals, but only averages the normals of the subject
+/// (where `subject` is true), so a large background doesn't drag the
+/// average towards its own normals. Every normal is still rotated.
+pub fn reorient_subject_normals(normals: &NormalMatrix, subject: &[bool]) -> NormalMatrix {
+    let mut sum = Vector3::<f32>::zeros();
+    for (row, _) in normals
+        .row_iter()
+        .zip(subject)
+        .filter(|(_, inside)| **inside)
+    {
+        sum += row.transpose();
+    }
+    rotate_to_camera(normals, sum)
+}
+
+/// Rotates the normals so that `average_normal` faces the camera
+fn rotate_to_camera(normals: &NormalMatrix, average_normal: Vector3<f32>) -> NormalMatrix {
+    let average_normal = match average_normal.try_normalize(1e-6) {
+        None => return normals.clone(),
+        Some(x) => x,
+    };
     let rotation = Rotation3::rotation_between(&average_normal, &Vector3::z());
 
     // If the normals are already averaged, return a copy of the originals
@@ -83,6 +617,83 @@ pub fn reorient_normals(normals: &NormalMatrix) -> NormalMatrix {
     NormalMatrix::from_column_slice(new_normals.transpose().as_slice())
 }
 
+/// Expresses camera space normals relative to an object whose up
+/// vector in camera space is `up`, by rotating `up` onto +z. Returns
+/// None if `up` is zero.
+pub fn to_object_space(normals: &NormalMatrix, up: &Vector3<f32>) -> Option<NormalMatrix> {
+    let up = up.try_normalize(1e-6)?;
+    let rotation = Rotation3::rotation_between(&up, &Vector3::z())
+        .unwrap_or_else(|| Rotation3::from_axis_angle(&Vector3::x_axis(), std::f32::consts::PI));
+    let rotation_matrix: Matrix3<f32> = rotation.into();
+    let rotated = rotation_matrix * normals.transpose();
+    Some(NormalMatrix::from_column_slice(
+        rotated.transpose().as_slice(),
+    ))
+}
+
+/// Negates the y component of each normal, converting between the
+/// DirectX (+Y down) and OpenGL (+Y up) conventions for normal maps
+pub fn flip_green(normals: &NormalMatrix) -> NormalMatrix {
+    let mut flipped = normals.clone();
+    flipped.column_mut(1).neg_mut();
+    flipped
+}
+
+/// Replaces the normals outside the subject (where `subject` is false)
+/// with forward facing normals
+pub fn flat_fill(normals: &NormalMatrix, subject: &[bool]) -> NormalMatrix {
+    let mut filled = normals.clone();
+    for (mut row, inside) in filled.row_iter_mut().zip(subject) {
+        if !inside {
+            row.copy_from(&Vector3::z().transpose());
+        }
+    }
+    filled
+}
+
+/// Projects normals that face away from the camera (negative z) onto
+/// the horizon, by zeroing z and renormalizing. A normal pointing
+/// straight back has no horizon direction, so it faces forwards instead.
+pub fn clamp_to_hemisphere(normals: &NormalMatrix) -> NormalMatrix {
+    let mut clamped = normals.clone();
+    for mut row in clamped.row_iter_mut() {
+        if row[2] >= 0.0 {
+            continue;
+        }
+        let horizon = Vector3::new(row[0], row[1], 0.0)
+            .try_normalize(0.0)
+            .unwrap_or(Vector3::z());
+        row.copy_from(&horizon.transpose());
+    }
+    clamped
+}
+
+/// Converts object space normals into the tangent space of a surface,
+/// given the tangent, bitangent, and normal of the surface at each
+/// pixel (such as from a baked UV/geometry map). Each row of the
+/// result is (normal . tangent, normal . bitangent, normal . base),
+/// so a normal matching the surface becomes (0, 0, 1).
+pub fn to_tangent_space(
+    normals: &NormalMatrix,
+    tangents: &NormalMatrix,
+    bitangents: &NormalMatrix,
+    normals_base: &NormalMatrix,
+) -> NormalMatrix {
+    let mut result = normals.clone();
+    for i in 0..result.nrows() {
+        let normal = normals.row(i);
+        let tangent_normal = Vector3::new(
+            normal.dot(&tangents.row(i)),
+            normal.dot(&bitangents.row(i)),
+            normal.dot(&normals_base.row(i)),
+        )
+        .try_normalize(0.0)
+        .unwrap_or(Vector3::z());
+        result.set_row(i, &tangent_normal.transpose());
+    }
+    result
+}
+
 pub fn normal_tilt(
     normals: &NormalMatrix,
     size: &Vector2<usize>,
@@ -116,6 +727,33 @@ pub fn normal_tilt(
     result
 }
 
+/// Averages each normal with its neighbours in a 3x3 block, then
+/// renormalizes, to suppress noise. Edge pixels average the
+/// neighbours that exist.
+pub fn smooth_normals(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatrix {
+    let (width, height) = (size[0], size[1]);
+    let mut smoothed = NormalMatrix::zeros(normals.nrows());
+    for y in 0..height {
+        for x in 0..width {
+            let mut total = Vector3::<f32>::zeros();
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let i = ny * width + nx;
+                    total += Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+                }
+            }
+            let i = y * width + x;
+            let average = total.try_normalize(0.0).unwrap_or(Vector3::new(
+                normals[(i, 0)],
+                normals[(i, 1)],
+                normals[(i, 2)],
+            ));
+            smoothed.set_row(i, &average.transpose());
+        }
+    }
+    smoothed
+}
+
 // Finds the average normal corners of the edges of the image.
 // Normals are then rotated with linear interpolation between the corners.
 // Note that this assumes edge normals face forwards.
@@ -124,8 +762,10 @@ pub fn normal_tilt(
 pub fn corner_flatten(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatrix {
     let top = normals.rows_range(0..size[0]);
     let bottom = normals.rows_range(normals.nrows() - size[0]..normals.nrows());
-    let left = normals.rows_with_step(0, size[1], size[0]);
-    let right = normals.rows_with_step(size[0] - 1, size[1] - 1, size[0]);
+    // rows_with_step skips `step` rows between each one it takes
+    let left = normals.rows_with_step(0, size[1], size[0] - 1);
+    let right = normals.rows_with_step(size[0] - 1, size[1], size[0] - 1);
+    debug_assert_eq!(left.nrows(), right.nrows());
     let upper_left = top.rows_range(0..top.nrows() / 2).row_sum()
         + left.rows_range(0..left.nrows() / 2).row_sum();
     let upper_left = upper_left.normalize().transpose();
@@ -133,7 +773,7 @@ pub fn corner_flatten(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMa
         + left.rows_range(left.nrows() / 2..left.nrows()).row_sum();
     let lower_left = lower_left.normalize().transpose();
     let upper_right = top.rows_range(top.nrows() / 2..top.nrows()).row_sum()
-        + right.rows_range(0..left.nrows() / 2).row_sum();
+        + right.rows_range(0..right.nrows() / 2).row_sum();
     let upper_right = upper_right.normalize().transpose();
     let lower_right = bottom
         .rows_range(bottom.nrows() / 2..bottom.nrows())
@@ -162,23 +802,22 @@ pub fn edge_flatten(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatr
         .view_range(normals.nrows() - size[0]..normals.nrows(), 0..3)
         .row_mean()
         .normalize();
-    let left = normals
-        .rows_with_step(0, size[1], size[0])
-        .row_mean()
-        .normalize();
-    let right = normals
-        .rows_with_step(size[0] - 1, size[1] - 1, size[0])
-        .row_mean()
-        .normalize();
+    // rows_with_step skips `step` rows between each one it takes
+    let left = normals.rows_with_step(0, size[1], size[0] - 1);
+    let right = normals.rows_with_step(size[0] - 1, size[1], size[0] - 1);
+    debug_assert_eq!(left.nrows(), right.nrows());
+    let left = left.row_mean().normalize();
+    let right = right.row_mean().normalize();
     let mut result = normals.clone();
     for i in 0..result.nrows() {
         // get coordinates as a fraction of the image size
         let (i_x, i_y) = i_to_xy(i);
         let f_x = i_x as f32 / size[0] as f32;
         let f_y = i_y as f32 / size[1] as f32;
-        // Estimate "flat" at this coordinate
+        // Estimate "flat" at this coordinate. The weights sum to 2, but
+        // the vector is normalized, so they needn't be halved.
         let alignment_vector =
-            left.scale(1.0 - f_x) + right.scale(f_x) + top.scale(1.0 - f_y) + bottom.scale(f_x);
+            left.scale(1.0 - f_x) + right.scale(f_x) + top.scale(1.0 - f_y) + bottom.scale(f_y);
         let alignment_vector = Vector3::from_column_slice(alignment_vector.as_slice()).normalize();
         // Rotate to flatten
         let rotation = Rotation3::rotation_between(&alignment_vector, &Vector3::z())
@@ -190,3 +829,514 @@ pub fn edge_flatten(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatr
     }
     result
 }
+
+/// Combines edge_flatten and corner_flatten. The mean normals of the
+/// edges give the tilt that changes linearly across and down the
+/// image, and the corners give the twist that changes with both at
+/// once, which neither method captures alone. Each normal is rotated
+/// so the combined estimate of "flat" at its position faces forwards.
+pub fn combined_flatten(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatrix {
+    let (width, height) = (size[0], size[1]);
+    let normal = |i: usize| Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+    let edge =
+        |pixels: Vec<usize>| -> Vec<Vector3<f32>> { pixels.into_iter().map(normal).collect() };
+    let top = edge((0..width).collect());
+    let bottom = edge((0..width).map(|x| (height - 1) * width + x).collect());
+    let left = edge((0..height).map(|y| y * width).collect());
+    let right = edge((0..height).map(|y| y * width + width - 1).collect());
+    let mean = |normals: &[Vector3<f32>]| {
+        normals.iter().sum::<Vector3<f32>>() / normals.len().max(1) as f32
+    };
+    // Each corner averages the halves of the two edges that meet there
+    let corner = |horizontal: &[Vector3<f32>], vertical: &[Vector3<f32>]| {
+        mean(&[horizontal, vertical].concat())
+    };
+    let (top_left, top_right) = top.split_at(width / 2);
+    let (bottom_left, bottom_right) = bottom.split_at(width / 2);
+    let (left_top, left_bottom) = left.split_at(height / 2);
+    let (right_top, right_bottom) = right.split_at(height / 2);
+    let upper_left = corner(top_left, left_top);
+    let upper_right = corner(top_right, right_top);
+    let lower_left = corner(bottom_left, left_bottom);
+    let lower_right = corner(bottom_right, right_bottom);
+
+    // "Flat" modelled as center + across x + down y + twist x y, with
+    // x and y measured from the center of the image. Edge means hold
+    // the linear terms exactly, and the corners (each centered about
+    // 3/8 of the way in from the middle) give twist / 8.
+    let (top, bottom) = (mean(&top), mean(&bottom));
+    let (left, right) = (mean(&left), mean(&right));
+    let center = (top + bottom + left + right) / 4.0;
+    let across = right - left;
+    let down = bottom - top;
+    let twist = (upper_left - upper_right - lower_left + lower_right) * 2.0;
+
+    let mut result = normals.clone();
+    for i in 0..result.nrows() {
+        let f_x = (i % width) as f32 / width as f32 - 0.5;
+        let f_y = (i / width) as f32 / height as f32 - 0.5;
+        let alignment = center + across * f_x + down * f_y + twist * (f_x * f_y);
+        let Some(alignment) = alignment.try_normalize(1e-6) else {
+            continue;
+        };
+        let rotation =
+            Rotation3::rotation_between(&alignment, &Vector3::z()).unwrap_or(Rotation3::identity());
+        let aligned = rotation * result.row(i).transpose();
+        result.set_row(i, &aligned.normalize().transpose());
+    }
+    result
+}
+
+/// Finds the surface gradient (dh/dx, dh/dy) implied by each normal,
+/// as height x width matrices. z is taken as the camera axis, with
+/// x to the right and y down the image, so a normal of (nx, ny, nz)
+/// has gradient (-nx/nz, -ny/nz). nz is kept away from zero to avoid
+/// infinite slopes.
+pub fn normal_gradients(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+) -> (DMatrix<f32>, DMatrix<f32>) {
+    let slope = |i: usize, axis: usize| -normals[(i, axis)] / normals[(i, 2)].max(1e-3);
+    let dx = DMatrix::from_fn(size[1], size[0], |y, x| slope(y * size[0] + x, 0));
+    let dy = DMatrix::from_fn(size[1], size[0], |y, x| slope(y * size[0] + x, 1));
+    (dx, dy)
+}
+
+/// Finds the mean absolute curl of the gradient implied by the normals.
+/// The normals of a real surface have a curl free gradient,
+/// so high values indicate an inconsistent normal field.
+pub fn integrability_error(normals: &NormalMatrix, size: &Vector2<usize>) -> f32 {
+    if size[0] < 2 || size[1] < 2 {
+        return 0.0;
+    }
+    let (dx, dy) = normal_gradients(normals, size);
+    let mut total_curl = 0.0;
+    for y in 0..size[1] - 1 {
+        for x in 0..size[0] - 1 {
+            let curl = (dx[(y + 1, x)] - dx[(y, x)]) - (dy[(y, x + 1)] - dy[(y, x)]);
+            total_curl += curl.abs();
+        }
+    }
+    total_curl / ((size[0] - 1) * (size[1] - 1)) as f32
+}
+
+/// Finds the height field whose forward differences best match the
+/// given gradients (height x width matrices) in the least squares
+/// sense. This solves the Poisson equation with conjugate gradient,
+/// and the result has a mean height of 0.
+pub fn integrate_gradients(dx: &DMatrix<f32>, dy: &DMatrix<f32>) -> DMatrix<f32> {
+    let (rows, cols) = dx.shape();
+    let forward_difference = |heights: &DMatrix<f32>| {
+        let dx = DMatrix::from_fn(rows, cols, |y, x| {
+            if x + 1 < cols {
+                heights[(y, x + 1)] - heights[(y, x)]
+            } else {
+                0.0
+            }
+        });
+        let dy = DMatrix::from_fn(rows, cols, |y, x| {
+            if y + 1 < rows {
+                heights[(y + 1, x)] - heights[(y, x)]
+            } else {
+                0.0
+            }
+        });
+        (dx, dy)
+    };
+    // Transpose of the forward difference, ignoring the last column of
+    // dx and the last row of dy, which have no forward difference
+    let divergence = |dx: &DMatrix<f32>, dy: &DMatrix<f32>| {
+        DMatrix::from_fn(rows, cols, |y, x| {
+            let mut result = 0.0;
+            if x > 0 {
+                result += dx[(y, x - 1)];
+            }
+            if x + 1 < cols {
+                result -= dx[(y, x)];
+            }
+            if y > 0 {
+                result += dy[(y - 1, x)];
+            }
+            if y + 1 < rows {
+                result -= dy[(y, x)];
+            }
+            result
+        })
+    };
+
+    // Conjugate gradient for (DᵀD)h = Dᵀg, where D is the forward difference
+    let b = divergence(dx, dy);
+    let tolerance = b.norm_squared() * 1e-10;
+    let mut heights = DMatrix::<f32>::zeros(rows, cols);
+    let mut residual = b;
+    let mut direction = residual.clone();
+    let mut residual_squared = residual.norm_squared();
+    for _ in 0..4 * (rows + cols) + 100 {
+        if residual_squared <= tolerance {
+            break;
+        }
+        let (direction_dx, direction_dy) = forward_difference(&direction);
+        let step = divergence(&direction_dx, &direction_dy);
+        let alpha = residual_squared / direction.dot(&step);
+        heights += alpha * &direction;
+        residual -= alpha * step;
+        let new_residual_squared = residual.norm_squared();
+        direction = &residual + (new_residual_squared / residual_squared) * direction;
+        residual_squared = new_residual_squared;
+    }
+    heights.add_scalar_mut(-heights.mean());
+    heights
+}
+
+/// Integrates the normals into a height field (a height x width
+/// matrix) with the Frankot-Chellappa method, which projects the
+/// gradient (see normal_gradients) onto the nearest integrable one in
+/// the Fourier domain. As elsewhere in the crate, z is the camera axis,
+/// so heights rise towards the camera, and normals with nz near zero
+/// are given a steep but finite slope. The image is treated as
+/// periodic, and the heights are stretched to [0, 1], ready for
+/// output_utils::height_image.
+pub fn integrate_heights(normals: &NormalMatrix, size: &Vector2<usize>) -> DMatrix<f32> {
+    let (rows, cols) = (size[1], size[0]);
+    let (dx, dy) = normal_gradients(normals, size);
+    let to_complex = |m: &DMatrix<f32>| m.map(|value| na::Complex::new(value as f64, 0.0));
+    let p = dft_2d(&to_complex(&dx), false);
+    let q = dft_2d(&to_complex(&dy), false);
+
+    // Angular frequency of each bin, wrapped to [-pi, pi)
+    let frequency = |bin: usize, count: usize| {
+        let bin = if 2 * bin >= count {
+            bin as f64 - count as f64
+        } else {
+            bin as f64
+        };
+        2.0 * std::f64::consts::PI * bin / count as f64
+    };
+    let spectrum = DMatrix::from_fn(rows, cols, |v, u| {
+        let (wx, wy) = (frequency(u, cols), frequency(v, rows));
+        let denominator = wx * wx + wy * wy;
+        if denominator == 0.0 {
+            return na::Complex::new(0.0, 0.0);
+        }
+        -na::Complex::<f64>::i() * (p[(v, u)] * wx + q[(v, u)] * wy) / denominator
+    });
+    let heights = dft_2d(&spectrum, true).map(|value| value.re as f32);
+
+    let (low, high) = (heights.min(), heights.max());
+    if high - low <= f32::EPSILON {
+        return DMatrix::zeros(rows, cols);
+    }
+    heights.map(|height| (height - low) / (high - low))
+}
+
+/// Discrete Fourier transform of each row, then each column. The
+/// inverse is scaled by 1 / (rows x cols).
+fn dft_2d(values: &DMatrix<na::Complex<f64>>, inverse: bool) -> DMatrix<na::Complex<f64>> {
+    let (rows, cols) = values.shape();
+    let mut result = values.clone();
+    let row_plan = FourierPlan::new(cols, inverse);
+    for y in 0..rows {
+        let row: Vec<_> = result.row(y).iter().copied().collect();
+        for (x, value) in row_plan.transform(&row).into_iter().enumerate() {
+            result[(y, x)] = value;
+        }
+    }
+    let column_plan = FourierPlan::new(rows, inverse);
+    for x in 0..cols {
+        let transformed = column_plan.transform(result.column(x).as_slice());
+        result.column_mut(x).copy_from_slice(&transformed);
+    }
+    if inverse {
+        result /= na::Complex::new((rows * cols) as f64, 0.0);
+    }
+    result
+}
+
+/// Precomputed factors for unscaled one dimensional discrete Fourier
+/// transforms of one length, which take O(n log n). Power of two
+/// lengths use a radix-2 FFT. Other lengths use Bluestein's algorithm,
+/// which rewrites the transform as a convolution done with power of two
+/// FFTs.
+struct FourierPlan {
+    count: usize,
+    /// Twiddle factors for the radix-2 FFT, of the padded length for
+    /// Bluestein's algorithm
+    twiddles: Vec<na::Complex<f64>>,
+    /// For Bluestein's algorithm, the chirp exp(±πi n² / count). Empty
+    /// for power of two lengths.
+    chirp: Vec<na::Complex<f64>>,
+    /// For Bluestein's algorithm, the FFT of the conjugate chirp, padded
+    /// and wrapped for a circular convolution
+    kernel: Vec<na::Complex<f64>>,
+}
+
+impl FourierPlan {
+    fn new(count: usize, inverse: bool) -> Self {
+        let sign = if inverse { 1.0 } else { -1.0 };
+        let unit = |angle: f64| na::Complex::new(angle.cos(), angle.sin());
+        if count.is_power_of_two() || count <= 1 {
+            return Self {
+                count,
+                twiddles: fft_twiddles(count, sign),
+                chirp: Vec::new(),
+                kernel: Vec::new(),
+            };
+        }
+        // nk = (n² + k² - (k - n)²) / 2, so the transform is a chirp
+        // times the convolution of the chirped input with the conjugate
+        // chirp. n² is wrapped mod 2n to keep the angles accurate.
+        let chirp: Vec<na::Complex<f64>> = (0..count)
+            .map(|n| {
+                let wrapped = ((n * n) % (2 * count)) as f64;
+                unit(sign * std::f64::consts::PI * wrapped / count as f64)
+            })
+            .collect();
+        let padded = (2 * count - 1).next_power_of_two();
+        // The convolution is done as a forward FFT whatever the
+        // direction of the whole transform
+        let twiddles = fft_twiddles(padded, -1.0);
+        let mut kernel = vec![na::Complex::new(0.0, 0.0); padded];
+        for n in 0..count {
+            kernel[n] = chirp[n].conj();
+            if n > 0 {
+                kernel[padded - n] = chirp[n].conj();
+            }
+        }
+        fft_radix2(&mut kernel, &twiddles);
+        Self {
+            count,
+            twiddles,
+            chirp,
+            kernel,
+        }
+    }
+
+    fn transform(&self, values: &[na::Complex<f64>]) -> Vec<na::Complex<f64>> {
+        debug_assert_eq!(values.len(), self.count);
+        let (chirp, kernel) = (&self.chirp, &self.kernel);
+        if kernel.is_empty() {
+            let mut result = values.to_vec();
+            fft_radix2(&mut result, &self.twiddles);
+            return result;
+        }
+        let padded = kernel.len();
+        let mut convolution = vec![na::Complex::new(0.0, 0.0); padded];
+        for n in 0..self.count {
+            convolution[n] = values[n] * chirp[n];
+        }
+        fft_radix2(&mut convolution, &self.twiddles);
+        // Multiply the spectra, then transform back by conjugating
+        // around a forward FFT
+        for (value, kernel) in convolution.iter_mut().zip(kernel) {
+            *value = (*value * kernel).conj();
+        }
+        fft_radix2(&mut convolution, &self.twiddles);
+        (0..self.count)
+            .map(|k| chirp[k] * convolution[k].conj() / padded as f64)
+            .collect()
+    }
+}
+
+/// Twiddle factors exp(sign 2πi k / count) for fft_radix2
+fn fft_twiddles(count: usize, sign: f64) -> Vec<na::Complex<f64>> {
+    (0..count / 2)
+        .map(|k| {
+            let angle = sign * 2.0 * std::f64::consts::PI * k as f64 / count as f64;
+            na::Complex::new(angle.cos(), angle.sin())
+        })
+        .collect()
+}
+
+/// Unscaled in place radix-2 FFT, using twiddle factors from
+/// fft_twiddles, whose sign sets the direction. The length must be a
+/// power of two.
+fn fft_radix2(values: &mut [na::Complex<f64>], twiddles: &[na::Complex<f64>]) {
+    let count = values.len();
+    if count <= 1 {
+        return;
+    }
+    // Put the values in bit reversed order
+    let mut j = 0;
+    for i in 1..count {
+        let mut bit = count >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j ^= bit;
+        if i < j {
+            values.swap(i, j);
+        }
+    }
+    let mut length = 2;
+    while length <= count {
+        let stride = count / length;
+        for start in (0..count).step_by(length) {
+            for k in 0..length / 2 {
+                let even = values[start + k];
+                let odd = values[start + k + length / 2] * twiddles[k * stride];
+                values[start + k] = even + odd;
+                values[start + k + length / 2] = even - odd;
+            }
+        }
+        length *= 2;
+    }
+}
+
+/// Finds the normals of a height field (a height x width matrix),
+/// using forward differences, or backward differences on the last
+/// row and column.
+pub fn height_normals(heights: &DMatrix<f32>) -> NormalMatrix {
+    let (rows, cols) = heights.shape();
+    let mut normals = Vec::<f32>::new();
+    for y in 0..rows {
+        for x in 0..cols {
+            let dx = if x + 1 < cols {
+                heights[(y, x + 1)] - heights[(y, x)]
+            } else if x > 0 {
+                heights[(y, x)] - heights[(y, x - 1)]
+            } else {
+                0.0
+            };
+            let dy = if y + 1 < rows {
+                heights[(y + 1, x)] - heights[(y, x)]
+            } else if y > 0 {
+                heights[(y, x)] - heights[(y - 1, x)]
+            } else {
+                0.0
+            };
+            normals.extend_from_slice(Vector3::new(-dx, -dy, 1.0).normalize().as_slice());
+        }
+    }
+    NormalMatrix::from_row_slice(&normals)
+}
+
+/// Replaces the normals with the nearest integrable normal field, by
+/// integrating their gradient into a height field, then finding the
+/// normals of that height field. This removes noise that is
+/// inconsistent with any real surface.
+pub fn enforce_integrability(normals: &NormalMatrix, size: &Vector2<usize>) -> NormalMatrix {
+    let (dx, dy) = normal_gradients(normals, size);
+    height_normals(&integrate_gradients(&dx, &dy))
+}
+
+/// Upsamples a low resolution normal field to the size of a high
+/// resolution guide image, such as the albedo, with joint bilateral
+/// upsampling. Each output normal is a weighted average of nearby low
+/// resolution normals, favouring those that are close, and whose guide
+/// brightness is similar, so edges in the guide are kept sharp.
+pub fn upscale_normals_guided(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    guide: &DynamicImage,
+) -> NormalMatrix {
+    // Spatial deviation in low resolution pixels, and brightness deviation
+    const SPATIAL_SIGMA: f32 = 1.0;
+    const RANGE_SIGMA: f32 = 0.1;
+    const RADIUS: isize = 2;
+
+    let guide = guide.to_luma32f();
+    let (width, height) = (guide.width() as usize, guide.height() as usize);
+    let scale = Vector2::new(
+        size[0] as f32 / width as f32,
+        size[1] as f32 / height as f32,
+    );
+    let guide_at = |x: f32, y: f32| {
+        let x = (x as usize).min(width - 1);
+        let y = (y as usize).min(height - 1);
+        guide.get_pixel(x as u32, y as u32).0[0]
+    };
+
+    let upscaled: Vec<f32> = (0..width * height)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let (x, y) = (i % width, i / width);
+            let brightness = guide_at(x as f32, y as f32);
+            // Position of this pixel's center in the low resolution field
+            let low_x = (x as f32 + 0.5) * scale[0] - 0.5;
+            let low_y = (y as f32 + 0.5) * scale[1] - 0.5;
+            let mut total = Vector3::<f32>::zeros();
+            for dy in -RADIUS..=RADIUS {
+                for dx in -RADIUS..=RADIUS {
+                    let qx = low_x.round() as isize + dx;
+                    let qy = low_y.round() as isize + dy;
+                    if qx < 0 || qy < 0 || qx >= size[0] as isize || qy >= size[1] as isize {
+                        continue;
+                    }
+                    let distance_squared =
+                        (qx as f32 - low_x).powi(2) + (qy as f32 - low_y).powi(2);
+                    let q_brightness =
+                        guide_at((qx as f32 + 0.5) / scale[0], (qy as f32 + 0.5) / scale[1]);
+                    let weight = (-distance_squared / (2.0 * SPATIAL_SIGMA.powi(2))
+                        - (brightness - q_brightness).powi(2) / (2.0 * RANGE_SIGMA.powi(2)))
+                    .exp();
+                    let q = qy as usize * size[0] + qx as usize;
+                    total += weight * normals.row(q).transpose();
+                }
+            }
+            let normal = total.try_normalize(0.0).unwrap_or(Vector3::z());
+            Vec::from(normal.as_slice())
+        })
+        .collect();
+    NormalMatrix::from_row_slice(&upscaled)
+}
+
+const NORMAL_FIELD_MAGIC: &[u8; 4] = b"NFSN";
+const NORMAL_FIELD_VERSION: u32 = 1;
+
+/// Saves a normal matrix and its image size with full precision.
+///
+/// The format is the magic bytes "NFSN", a u32 version, the u64 width
+/// and height, then the xyz of each normal as f32, all little endian.
+pub fn save_normal_field(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    path: &str,
+) -> io::Result<()> {
+    let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+    file.write_all(NORMAL_FIELD_MAGIC)?;
+    file.write_all(&NORMAL_FIELD_VERSION.to_le_bytes())?;
+    file.write_all(&(size[0] as u64).to_le_bytes())?;
+    file.write_all(&(size[1] as u64).to_le_bytes())?;
+    for normal in normals.row_iter() {
+        for channel in normal.iter() {
+            file.write_all(&channel.to_le_bytes())?;
+        }
+    }
+    file.flush()
+}
+
+/// Loads a normal matrix and its image size saved by save_normal_field
+pub fn load_normal_field(path: &str) -> io::Result<(NormalMatrix, Vector2<usize>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut data = Vec::<u8>::new();
+    std::fs::File::open(path)?.read_to_end(&mut data)?;
+    if data.len() < 24 || &data[0..4] != NORMAL_FIELD_MAGIC {
+        return Err(invalid("Not a normal field file"));
+    }
+    let version = u32::from_le_bytes(data[4..8].try_into().unwrap());
+    if version != NORMAL_FIELD_VERSION {
+        return Err(invalid("Unsupported normal field version"));
+    }
+    let dimension = |bytes: &[u8]| usize::try_from(u64::from_le_bytes(bytes.try_into().unwrap()));
+    let (width, height) = match (dimension(&data[8..16]), dimension(&data[16..24])) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(invalid("Normal field size is too large")),
+    };
+    // The header is untrusted, so the expected length must not overflow
+    let expected = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(3 * 4))
+        .and_then(|bytes| bytes.checked_add(24));
+    if expected != Some(data.len()) {
+        return Err(invalid("Normal field data doesn't match its size"));
+    }
+    let channels: Vec<f32> = data[24..]
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+    Ok((
+        NormalMatrix::from_row_slice(&channels),
+        Vector2::new(width, height),
+    ))
+}
@@ -0,0 +1,664 @@
+use image::codecs::png::PngEncoder;
+use image::error::{ImageError, ParameterError, ParameterErrorKind};
+use image::{
+    DynamicImage, ExtendedColorType, GrayImage, ImageBuffer, ImageEncoder, ImageResult, Luma, Rgb,
+    RgbImage,
+};
+use na::{DMatrix, Matrix3, Vector2, Vector3};
+use std::io::{self, Write};
+
+use crate::normal_utils::*;
+use crate::radiance_map::*;
+
+/// Which way the green channel of an encoded normal map faces.
+///
+/// Normals in this crate have y pointing down the image, which is
+/// the DirectX convention. OpenGL expects y to point up the image,
+/// so the y component is negated when encoding for OpenGL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NormalConvention {
+    /// +Y points down the image
+    #[default]
+    DirectX,
+    /// +Y points up the image
+    OpenGl,
+}
+
+/// How each normal component is stored in a byte
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NormalEncoding {
+    /// Unsigned, mapping [-1, 1] to [0, 255] with 0 at 128
+    #[default]
+    Unsigned,
+    /// Signed normalized (SNORM), as used by GPU formats. Each byte is
+    /// a two's complement i8, mapping [-1, 1] to [-127, 127] with 0 at 0.
+    Snorm,
+}
+
+/// Encodes normals as RGB bytes in the given convention,
+/// mapping each component from [-1, 1] to [0, 255].
+pub fn normal_bytes(normals: &NormalMatrix, convention: NormalConvention) -> Vec<u8> {
+    encode_normal_bytes(normals, convention, NormalEncoding::Unsigned)
+}
+
+/// Encodes normals as RGB bytes in the given convention and encoding
+pub fn encode_normal_bytes(
+    normals: &NormalMatrix,
+    convention: NormalConvention,
+    encoding: NormalEncoding,
+) -> Vec<u8> {
+    let y_sign = match convention {
+        NormalConvention::DirectX => 1.0,
+        NormalConvention::OpenGl => -1.0,
+    };
+    normals
+        .row_iter()
+        .flat_map(|normal| [normal[0], normal[1] * y_sign, normal[2]])
+        .map(|channel| match encoding {
+            // +1 maps to 256, so clamp to the top of the range
+            NormalEncoding::Unsigned => (channel * 128.0 + 128.0).clamp(0.0, 255.0) as u8,
+            NormalEncoding::Snorm => (channel.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8,
+        })
+        .collect()
+}
+
+/// Options for normals_to_image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NormalImageOptions {
+    pub convention: NormalConvention,
+    pub encoding: NormalEncoding,
+    /// A rotation or reflection applied to every normal before the
+    /// convention, to map the crate's axes (x right, y down the image,
+    /// z towards the camera) onto another engine's. Row i gives output
+    /// component i, so swapping y and z makes z the second channel.
+    /// None by default.
+    pub axes: Option<Matrix3<f32>>,
+}
+
+/// Applies an axis mapping (see NormalImageOptions::axes) to every
+/// normal. Returns an error unless the matrix is orthonormal, as a
+/// rotation or reflection is, so unit normals stay unit length.
+pub fn remap_axes(normals: &NormalMatrix, axes: &Matrix3<f32>) -> Result<NormalMatrix, String> {
+    if (axes.transpose() * axes - Matrix3::identity()).amax() > 1e-4 {
+        return Err("The axis mapping must be a rotation or reflection".to_string());
+    }
+    let remapped = axes * normals.transpose();
+    Ok(NormalMatrix::from_column_slice(
+        remapped.transpose().as_slice(),
+    ))
+}
+
+/// Encodes a normal field as an RGB image, exactly as the normal maps
+/// generated by this crate are encoded.
+pub fn normals_to_image(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    options: NormalImageOptions,
+) -> Result<DynamicImage, String> {
+    if normals.nrows() != size.product() {
+        return Err("Normal output wasn't the right size".to_string());
+    }
+    let remapped;
+    let normals = match &options.axes {
+        None => normals,
+        Some(axes) => {
+            remapped = remap_axes(normals, axes)?;
+            &remapped
+        }
+    };
+    let bytes = encode_normal_bytes(normals, options.convention, options.encoding);
+    match RgbImage::from_vec(size[0] as u32, size[1] as u32, bytes) {
+        None => Err("Normal output wasn't the right size".to_string()),
+        Some(x) => Ok(x.into()),
+    }
+}
+
+/// Encodes a normal field as a 16 bit RGB image, to avoid the banding
+/// of 8 bits on smooth surfaces. Each component maps from [-1, 1] to
+/// [1, 65535], with 0 at the center value of 32768.
+pub fn normals_to_image_16bit(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    convention: NormalConvention,
+) -> Result<DynamicImage, String> {
+    if normals.nrows() != size.product() {
+        return Err("Normal output wasn't the right size".to_string());
+    }
+    let y_sign = match convention {
+        NormalConvention::DirectX => 1.0,
+        NormalConvention::OpenGl => -1.0,
+    };
+    let channels: Vec<u16> = normals
+        .row_iter()
+        .flat_map(|normal| [normal[0], normal[1] * y_sign, normal[2]])
+        .map(|channel| (32768.0 + (channel.clamp(-1.0, 1.0) * 32767.0).round()) as u16)
+        .collect();
+    match ImageBuffer::<Rgb<u16>, Vec<u16>>::from_vec(size[0] as u32, size[1] as u32, channels) {
+        None => Err("Normal output wasn't the right size".to_string()),
+        Some(x) => Ok(x.into()),
+    }
+}
+
+/// Decodes a normal map made with the default NormalImageOptions
+/// (such as by normals_to_image) back into unit normals and its size
+pub fn image_to_normals(image: &DynamicImage) -> (NormalMatrix, Vector2<usize>) {
+    let image = image.to_rgb8();
+    let size = Vector2::new(image.width() as usize, image.height() as usize);
+    let mut normals = NormalMatrix::zeros(size.product());
+    for (i, pixel) in image.pixels().enumerate() {
+        // Encoding rounds down, so decode to the middle of each step
+        let normal = Vector3::from_fn(|channel, _| (pixel.0[channel] as f32 - 127.5) / 128.0);
+        normals.set_row(
+            i,
+            &normal
+                .try_normalize(0.0)
+                .unwrap_or(Vector3::z())
+                .transpose(),
+        );
+    }
+    (normals, size)
+}
+
+/// How heights are mapped to brightness in a height image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HeightScaling {
+    /// Stretch the heights so the lowest point is black and the highest
+    /// is white, using the full range of the image
+    #[default]
+    Stretch,
+    /// Stretch as above, then apply a display gamma, raising each
+    /// stretched height to the power 1 / gamma. A gamma above 1 brings
+    /// out detail in the low areas. The gamma must be finite and
+    /// greater than 0.
+    Gamma(f32),
+}
+
+/// Encodes a height field (a height x width matrix) as a 16 bit
+/// greyscale image, stretched so the lowest point is black and the
+/// highest is white.
+pub fn height_image(heights: &DMatrix<f32>) -> DynamicImage {
+    height_image_with_scaling(heights, HeightScaling::Stretch)
+        .expect("Stretching accepts any heights")
+}
+
+/// Encodes a height field as a 16 bit greyscale image, with the given
+/// mapping from height to brightness. Fails if the scaling is a gamma
+/// that isn't finite and greater than 0.
+pub fn height_image_with_scaling(
+    heights: &DMatrix<f32>,
+    scaling: HeightScaling,
+) -> Result<DynamicImage, String> {
+    if let HeightScaling::Gamma(gamma) = scaling {
+        if !(gamma.is_finite() && gamma > 0.0) {
+            return Err(format!(
+                "Height gamma must be finite and above 0, got {}",
+                gamma
+            ));
+        }
+    }
+    let (low, high) = (heights.min(), heights.max());
+    let range = (high - low).max(f32::EPSILON);
+    let (rows, cols) = heights.shape();
+    let image = ImageBuffer::<Luma<u16>, Vec<u16>>::from_fn(cols as u32, rows as u32, |x, y| {
+        let height = (heights[(y as usize, x as usize)] - low) / range;
+        let brightness = match scaling {
+            HeightScaling::Stretch => height,
+            HeightScaling::Gamma(gamma) => height.powf(1.0 / gamma),
+        };
+        Luma([(brightness * 65535.0).round() as u16])
+    });
+    Ok(image.into())
+}
+
+/// Options for export_geometry
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GeometryExportConfig {
+    /// How the normal map is encoded
+    pub normal_options: NormalImageOptions,
+    /// How the height map is encoded. Defaults to stretching the
+    /// heights over the full range of the image.
+    pub height_scaling: HeightScaling,
+}
+
+/// Writes a normal field as `<base_path>_normal.png`, along with the
+/// height field integrated from it (see normal_utils::integrate_gradients)
+/// as a 16 bit `<base_path>_height.png`.
+///
+/// The image only holds the heights relative to each other, so the
+/// unquantized heights are returned as well.
+pub fn export_geometry(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    base_path: &str,
+    config: &GeometryExportConfig,
+) -> Result<DMatrix<f32>, String> {
+    let normal_path = format!("{}_normal.png", base_path);
+    normals_to_image(normals, size, config.normal_options)?
+        .save(&normal_path)
+        .map_err(|err| format!("Could not write {}: {}", normal_path, err))?;
+
+    let (dx, dy) = normal_gradients(normals, size);
+    let height_path = format!("{}_height.png", base_path);
+    let heights = integrate_gradients(&dx, &dy);
+    height_image_with_scaling(&heights, config.height_scaling)?
+        .save(&height_path)
+        .map_err(|err| format!("Could not write {}: {}", height_path, err))?;
+    Ok(heights)
+}
+
+/// Options for write_obj
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MeshExportConfig {
+    /// World space distance between neighboring pixels along x and y.
+    /// Defaults to 1.
+    pub xy_scale: f32,
+    /// World space distance for a height difference of 1, such as from
+    /// the bottom to the top of normal_utils::integrate_heights.
+    /// Defaults to 1.
+    pub z_scale: f32,
+}
+
+impl Default for MeshExportConfig {
+    fn default() -> Self {
+        Self {
+            xy_scale: 1.0,
+            z_scale: 1.0,
+        }
+    }
+}
+
+/// Writes a height field (a height x width matrix, such as from
+/// normal_utils::integrate_heights) as a triangulated OBJ mesh, with a
+/// vertex for each pixel, two triangles for each square of four
+/// pixels, and each vertex's normal taken from `normals`.
+///
+/// The mesh has y pointing up, so the image's y axis (and the y of
+/// the normals) is flipped. When a mask is given, pixels where it is
+/// false are left out, along with any triangle that touches them.
+pub fn write_obj<W: Write>(
+    heights: &DMatrix<f32>,
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    mask: Option<&[bool]>,
+    config: &MeshExportConfig,
+    mut writer: W,
+) -> io::Result<()> {
+    let (width, height) = (size[0], size[1]);
+    if heights.shape() != (height, width) || normals.nrows() != size.product() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The heights are {}x{} and there are {} normals, but the image is {}x{}",
+                heights.ncols(),
+                heights.nrows(),
+                normals.nrows(),
+                width,
+                height
+            ),
+        ));
+    }
+    // OBJ indices count from 1, and skip masked out pixels
+    let mut indices = vec![None; size.product()];
+    let mut next_index = 1;
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = y * width + x;
+            if mask.is_some_and(|mask| !mask[pixel]) {
+                continue;
+            }
+            indices[pixel] = Some(next_index);
+            next_index += 1;
+            writeln!(
+                writer,
+                "v {} {} {}",
+                x as f32 * config.xy_scale,
+                -(y as f32) * config.xy_scale,
+                heights[(y, x)] * config.z_scale
+            )?;
+            writeln!(
+                writer,
+                "vn {} {} {}",
+                normals[(pixel, 0)],
+                -normals[(pixel, 1)],
+                normals[(pixel, 2)]
+            )?;
+        }
+    }
+    for y in 0..height.saturating_sub(1) {
+        for x in 0..width.saturating_sub(1) {
+            let corner = |dx: usize, dy: usize| indices[(y + dy) * width + x + dx];
+            let (top_left, top_right) = (corner(0, 0), corner(1, 0));
+            let (bottom_left, bottom_right) = (corner(0, 1), corner(1, 1));
+            // Counterclockwise, seen from above
+            for triangle in [
+                [top_left, bottom_left, bottom_right],
+                [top_left, bottom_right, top_right],
+            ] {
+                if let [Some(a), Some(b), Some(c)] = triangle {
+                    writeln!(writer, "f {0}//{0} {1}//{1} {2}//{2}", a, b, c)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes normals to an OpenEXR file as three 32 bit float channels in
+/// [-1, 1], with no quantization. The channels hold x, y, and z as
+/// they are in the crate, with +y pointing down the image.
+#[cfg(feature = "exr")]
+pub fn save_normals_exr(
+    path: &str,
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+) -> Result<(), String> {
+    if normals.nrows() != size.product() {
+        return Err(format!(
+            "There are {} normals, but the image is {}x{}",
+            normals.nrows(),
+            size[0],
+            size[1]
+        ));
+    }
+    let image = image::Rgb32FImage::from_vec(
+        size[0] as u32,
+        size[1] as u32,
+        normals.transpose().as_slice().to_vec(),
+    )
+    .ok_or_else(|| "Normals should match their size".to_string())?;
+    image
+        .save_with_format(path, image::ImageFormat::OpenExr)
+        .map_err(|err| format!("Could not write {}: {}", path, err))
+}
+
+/// Writes an albedo to an OpenEXR file as linear float RGB. Float
+/// images are written as they are. 8 bit images, such as from
+/// generate_albedo, are decoded from sRGB to linear when `srgb` is set
+/// (see radiance_map::srgb_to_linear), and only scaled to [0, 1]
+/// otherwise.
+#[cfg(feature = "exr")]
+pub fn save_albedo_exr(path: &str, albedo: &DynamicImage, srgb: bool) -> Result<(), String> {
+    let is_float = matches!(
+        albedo,
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)
+    );
+    let mut linear = albedo.to_rgb32f();
+    if srgb && !is_float {
+        for channel in linear.iter_mut() {
+            *channel = srgb_to_linear(*channel);
+        }
+    }
+    linear
+        .save_with_format(path, image::ImageFormat::OpenExr)
+        .map_err(|err| format!("Could not write {}: {}", path, err))
+}
+
+/// Whether a normal map has an alpha channel, and what it holds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// RGB, with no alpha channel
+    #[default]
+    None,
+    /// RGBA, fully opaque everywhere
+    Opaque,
+    /// RGBA, transparent where the pixel is masked out of the subject
+    Mask,
+}
+
+/// Gives an image the alpha channel described by `mode`. For
+/// AlphaMode::Mask, pixels where `subject` is false are transparent,
+/// and every pixel is opaque if there is no subject mask.
+pub fn apply_alpha_mode(
+    image: DynamicImage,
+    mode: AlphaMode,
+    subject: Option<&[bool]>,
+) -> DynamicImage {
+    if mode == AlphaMode::None {
+        return image;
+    }
+    let mut rgba = image.to_rgba8();
+    for (i, pixel) in rgba.pixels_mut().enumerate() {
+        let inside = match (mode, subject) {
+            (AlphaMode::Mask, Some(subject)) => subject[i],
+            _ => true,
+        };
+        pixel.0[3] = if inside { 255 } else { 0 };
+    }
+    rgba.into()
+}
+
+/// Encodes normals as an RGB PNG straight into a writer,
+/// such as a network stream, without an intermediate image.
+/// Fails with a dimension mismatch if the normals don't fill the size.
+pub fn write_normal_map<W: Write>(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    convention: NormalConvention,
+    writer: W,
+) -> ImageResult<()> {
+    if normals.nrows() != size.product() {
+        return Err(ImageError::Parameter(ParameterError::from_kind(
+            ParameterErrorKind::DimensionMismatch,
+        )));
+    }
+    PngEncoder::new(writer).write_image(
+        &normal_bytes(normals, convention),
+        size[0] as u32,
+        size[1] as u32,
+        ExtendedColorType::Rgb8,
+    )
+}
+
+/// Renders the recovered surface under a new light, for checking the
+/// normals and albedo against the original images. Fails if the
+/// albedo doesn't have a pixel for each normal.
+///
+/// Each pixel's color is the albedo scaled by the ambient light, plus
+/// diffuse shading from the light direction.
+pub fn relight(
+    normals: &NormalMatrix,
+    albedo: &DynamicImage,
+    light: Vector3<f32>,
+    ambient: f32,
+) -> Result<DynamicImage, String> {
+    relight_specular(normals, albedo, light, ambient, 0.0, 1.0)
+}
+
+/// Like relight, but adds a white Blinn-Phong highlight of the given
+/// intensity and shininess, as seen by a camera looking along z.
+pub fn relight_specular(
+    normals: &NormalMatrix,
+    albedo: &DynamicImage,
+    light: Vector3<f32>,
+    ambient: f32,
+    specular_intensity: f32,
+    shininess: f32,
+) -> Result<DynamicImage, String> {
+    if albedo.width() as usize * albedo.height() as usize != normals.nrows() {
+        return Err("Albedo doesn't match the size of the normals".to_string());
+    }
+    let light = light.normalize();
+    let halfway = (light + Vector3::z()).normalize();
+    let mut result = albedo.to_rgba8();
+    for (i, pixel) in result.pixels_mut().enumerate() {
+        let normal = Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+        let diffuse = ambient + normal.dot(&light).max(0.0);
+        let specular = specular_intensity * normal.dot(&halfway).max(0.0).powf(shininess);
+        // Shade the color channels (except alpha)
+        for channel in &mut pixel.0[0..3] {
+            *channel = (*channel as f32 * diffuse + specular * 255.0).clamp(0.0, 255.0) as u8;
+        }
+    }
+    Ok(result.into())
+}
+
+/// How linear brightness is squeezed into [0, 1] for an 8 bit preview.
+/// HDR radiance, such as from EXR images, goes well above 1, and would
+/// mostly clip to white if clamped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneMapping {
+    /// Clip brightness above 1, which suits images that were already 8
+    /// bit
+    #[default]
+    Clamp,
+    /// Reinhard's x / (1 + x), which compresses highlights smoothly
+    /// and never reaches white
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve, which keeps more
+    /// contrast in the midtones than Reinhard
+    Aces,
+}
+
+impl ToneMapping {
+    /// Maps a linear brightness to [0, 1]
+    pub fn apply(&self, value: f32) -> f32 {
+        let value = value.max(0.0);
+        let mapped = match self {
+            ToneMapping::Clamp => value,
+            ToneMapping::Reinhard => value / (1.0 + value),
+            ToneMapping::Aces => {
+                (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)
+            }
+        };
+        mapped.clamp(0.0, 1.0)
+    }
+}
+
+/// Options for radiance_preview and shading_preview
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PreviewOptions {
+    /// How brightness above 1 is brought into range. Defaults to
+    /// clamping.
+    pub tone_mapping: ToneMapping,
+    /// Encode the tone mapped brightness as sRGB, so previews of
+    /// linearized radiance (see NormalMapConfig::linearize) look like
+    /// the original images. Off by default.
+    pub srgb: bool,
+}
+
+/// Tone maps a field of linear brightness into an 8 bit greyscale image
+fn tone_mapped_image(
+    values: &RadianceMatrix,
+    size: &Vector2<usize>,
+    options: PreviewOptions,
+) -> DynamicImage {
+    let bytes: Vec<u8> = values
+        .iter()
+        .map(|value| {
+            let mapped = options.tone_mapping.apply(*value);
+            let encoded = if options.srgb {
+                linear_to_srgb(mapped)
+            } else {
+                mapped
+            };
+            (encoded * 255.0).round() as u8
+        })
+        .collect();
+    GrayImage::from_vec(size[0] as u32, size[1] as u32, bytes)
+        .expect("Brightness should match its size")
+        .into()
+}
+
+/// An 8 bit preview of a radiance map. The radiance itself is left
+/// linear, so only the preview is tone mapped and encoded.
+pub fn radiance_preview(radiance_map: &RadianceMap, options: PreviewOptions) -> DynamicImage {
+    tone_mapped_image(&radiance_map.radiance, &radiance_map.size, options)
+}
+
+/// An 8 bit preview of the shading that the normals and albedo predict
+/// under a radiance map's light, albedo * max(normal . light, 0), to
+/// compare against radiance_preview of the same map.
+pub fn shading_preview(
+    normals: &NormalMatrix,
+    albedo: &RadianceMatrix,
+    radiance_map: &RadianceMap,
+    options: PreviewOptions,
+) -> DynamicImage {
+    let shading = RadianceMatrix::from_fn(normals.nrows(), |pixel, _| {
+        let normal = Vector3::new(
+            normals[(pixel, 0)],
+            normals[(pixel, 1)],
+            normals[(pixel, 2)],
+        );
+        albedo[pixel] * normal.dot(&radiance_map.lighting_direction).max(0.0)
+    });
+    tone_mapped_image(&shading, &radiance_map.size, options)
+}
+
+/// Shrinks a normal field so its longest side is at most max_dim, for
+/// previews. Resizing the encoded image would average bytes and skew
+/// the normals, so each thumbnail pixel averages its block of unit
+/// normals and renormalizes before encoding.
+pub fn thumbnail_normals(
+    normals: &NormalMatrix,
+    size: &Vector2<usize>,
+    max_dim: usize,
+) -> DynamicImage {
+    let longest = size[0].max(size[1]);
+    let scale = (max_dim as f32 / longest as f32).min(1.0);
+    let thumb_size = size.map(|side| ((side as f32 * scale).round() as usize).max(1));
+
+    let mut thumb = NormalMatrix::zeros(thumb_size.product());
+    for ty in 0..thumb_size[1] {
+        let (y_start, y_end) = (
+            ty * size[1] / thumb_size[1],
+            (ty + 1) * size[1] / thumb_size[1],
+        );
+        for tx in 0..thumb_size[0] {
+            let (x_start, x_end) = (
+                tx * size[0] / thumb_size[0],
+                (tx + 1) * size[0] / thumb_size[0],
+            );
+            let mut total = Vector3::<f32>::zeros();
+            for y in y_start..y_end {
+                for x in x_start..x_end {
+                    let i = y * size[0] + x;
+                    total += Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+                }
+            }
+            let average = total.try_normalize(0.0).unwrap_or(Vector3::z());
+            thumb.set_row(ty * thumb_size[0] + tx, &average.transpose());
+        }
+    }
+
+    normals_to_image(&thumb, &thumb_size, Default::default())
+        .expect("thumbnail normals match its size")
+}
+
+/// Angle in degrees at which normal_difference_image reaches its
+/// brightest color
+pub const DIFFERENCE_SCALE_DEGREES: f32 = 45.0;
+
+/// Shows where two normal fields of the same size differ, as a false
+/// color image of the angle between their normals. The scale is fixed,
+/// so images from different runs can be compared: identical normals
+/// are black, and the color rises through blue and red to yellow at
+/// DIFFERENCE_SCALE_DEGREES or more.
+pub fn normal_difference_image(
+    a: &NormalMatrix,
+    b: &NormalMatrix,
+    size: &Vector2<usize>,
+) -> Result<DynamicImage, String> {
+    if a.nrows() != size.product() || b.nrows() != size.product() {
+        return Err("Normal fields don't match the image size".to_string());
+    }
+    const STOPS: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 255.0],
+        [255.0, 0.0, 0.0],
+        [255.0, 255.0, 0.0],
+    ];
+    let image = RgbImage::from_fn(size[0] as u32, size[1] as u32, |x, y| {
+        let i = y as usize * size[0] + x as usize;
+        let dot = a.row(i).dot(&b.row(i)).clamp(-1.0, 1.0);
+        let t = (dot.acos().to_degrees() / DIFFERENCE_SCALE_DEGREES).clamp(0.0, 1.0);
+        let position = t * (STOPS.len() - 1) as f32;
+        let stop = (position as usize).min(STOPS.len() - 2);
+        let fraction = position - stop as f32;
+        Rgb([0, 1, 2].map(|channel| {
+            let (low, high) = (STOPS[stop][channel], STOPS[stop + 1][channel]);
+            (low + (high - low) * fraction).round() as u8
+        }))
+    });
+    Ok(image.into())
+}
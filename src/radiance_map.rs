@@ -1,5 +1,5 @@
-use image::{self, GenericImageView, ImageReader, ImageResult};
-use na::{Vector2, Vector3};
+use image::{self, ImageDecoder, ImageReader, ImageResult};
+use na::{DMatrix, Vector2, Vector3};
 
 pub type RadianceMatrix = na::Matrix<f32, na::Dyn, na::U1, na::VecStorage<f32, na::Dyn, na::U1>>;
 
@@ -11,37 +11,232 @@ pub struct RadianceMap {
     pub lighting_direction: Vector3<f32>,
     pub size: Vector2<usize>,
     pub radiance: RadianceMatrix,
+    /// Which pixels are part of the image, false where it was fully
+    /// transparent (see alpha_mask). None if every pixel is.
+    pub mask: Option<Vec<bool>>,
 }
 
+/// Weights of the red, green, and blue channels used to convert color
+/// to greyscale radiance (the Rec. 709 luma weights).
+pub const LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
 /// Creates a radiance map from a dynamic image,
 /// with a lighting direction along the z axis.
+///
+/// Radiance is the sum of the color channels, scaled to [0, 1], and
+/// weighted by LUMA_WEIGHTS. Float images, such as those loaded from
+/// EXR or HDR files, are read as they are, so brightness above 1 is
+/// kept rather than clamped.
 impl From<image::DynamicImage> for RadianceMap {
     fn from(image_data: image::DynamicImage) -> Self {
         let size = Vector2::new(image_data.width() as usize, image_data.height() as usize);
         let greyscale: Vec<f32> = image_data
-            .grayscale()
+            .to_rgb32f()
             .pixels()
             .map(|pixel| {
                 // convert to greyscale float
-                pixel.2 .0[0] as f32 / 255.0
+                (0..3).map(|i| pixel.0[i] * LUMA_WEIGHTS[i]).sum()
             })
             .collect();
         Self {
             lighting_direction: Vector3::<f32>::z(),
             size,
             radiance: RadianceMatrix::from_row_slice(&greyscale),
+            mask: alpha_mask(&image_data),
+        }
+    }
+}
+
+/// Reads the alpha channel of an image as a mask, which is false for
+/// each fully transparent pixel. Returns None if the image has no
+/// alpha channel, or no transparent pixels.
+pub fn alpha_mask(image: &image::DynamicImage) -> Option<Vec<bool>> {
+    if !image.color().has_alpha() {
+        return None;
+    }
+    let mask: Vec<bool> = image
+        .to_rgba32f()
+        .pixels()
+        .map(|pixel| pixel.0[3] > 0.0)
+        .collect();
+    if mask.iter().all(|opaque| *opaque) {
+        None
+    } else {
+        Some(mask)
+    }
+}
+
+/// Combines the masks of several radiance maps, so a pixel is only
+/// kept if no map masks it out. None if none of them have a mask.
+pub fn combined_mask(radiance_maps: &[RadianceMap]) -> Option<Vec<bool>> {
+    let mut masks = radiance_maps.iter().filter_map(|map| map.mask.as_ref());
+    let mut combined = masks.next()?.clone();
+    for mask in masks {
+        for (kept, opaque) in combined.iter_mut().zip(mask) {
+            *kept &= *opaque;
+        }
+    }
+    Some(combined)
+}
+
+/// Splits an image into a radiance map for each of its red, green, and
+/// blue channels, with lighting directions along the z axis
+pub fn color_channels(image: &image::DynamicImage) -> [RadianceMap; 3] {
+    let size = Vector2::new(image.width() as usize, image.height() as usize);
+    let rgb = image.to_rgb32f();
+    let mask = alpha_mask(image);
+    [0, 1, 2].map(|channel| RadianceMap {
+        lighting_direction: Vector3::<f32>::z(),
+        size,
+        radiance: RadianceMatrix::from_iterator(
+            size.product(),
+            rgb.pixels().map(|pixel| pixel.0[channel]),
+        ),
+        mask: mask.clone(),
+    })
+}
+
+/// Estimates the color of the light in an image from its overall
+/// color balance, assuming the scene is neutral grey on average. The
+/// result is scaled so its channels average 1.
+pub fn estimate_light_color(image: &image::DynamicImage) -> [f32; 3] {
+    let mut totals = [0.0f32; 3];
+    for pixel in image.to_rgb32f().pixels() {
+        for (total, channel) in totals.iter_mut().zip(pixel.0) {
+            *total += channel;
+        }
+    }
+    let mean = totals.iter().sum::<f32>() / 3.0;
+    if mean <= 0.0 {
+        return [1.0; 3];
+    }
+    totals.map(|total| total / mean)
+}
+
+/// Creates a radiance map like RadianceMap::from, but first divides
+/// out the color of the light (see estimate_light_color), so lights of
+/// different color temperatures give comparable brightness.
+pub fn radiance_without_light_color(image: &image::DynamicImage) -> RadianceMap {
+    let light_color = estimate_light_color(image);
+    let mut corrected = image.to_rgb32f();
+    for pixel in corrected.pixels_mut() {
+        for (channel, color) in pixel.0.iter_mut().zip(light_color) {
+            if color > 0.0 {
+                *channel /= color;
+            }
         }
     }
+    let mut radiance_map = RadianceMap::from(image::DynamicImage::from(corrected));
+    radiance_map.mask = alpha_mask(image);
+    radiance_map
+}
+
+/// A background color, such as a chroma key backdrop, to leave out of
+/// the solve
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorKey {
+    pub color: [u8; 3],
+    /// Largest distance from the key color, with each channel scaled
+    /// to [0, 1], that still counts as background
+    pub tolerance: f32,
+}
+
+/// Finds which pixels show the subject rather than the key color.
+/// Shading can take the backdrop out of tolerance under some lights,
+/// so a pixel is background if it is near the key in at least half of
+/// the images. The result holds true for each subject pixel.
+pub fn color_key_mask(images: &[image::DynamicImage], key: &ColorKey) -> Vec<bool> {
+    let Some(first) = images.first() else {
+        return Vec::new();
+    };
+    let key_color = key.color.map(|channel| channel as f32 / 255.0);
+    let mut keyed_count = vec![0usize; (first.width() * first.height()) as usize];
+    for image in images {
+        for (count, pixel) in keyed_count.iter_mut().zip(image.to_rgb32f().pixels()) {
+            let distance_squared: f32 = (0..3).map(|i| (pixel.0[i] - key_color[i]).powi(2)).sum();
+            if distance_squared <= key.tolerance * key.tolerance {
+                *count += 1;
+            }
+        }
+    }
+    keyed_count
+        .into_iter()
+        .map(|count| 2 * count < images.len())
+        .collect()
+}
+
+/// Reads a mask image, which is true for each pixel with a nonzero
+/// brightness
+pub fn image_mask(mask: &image::DynamicImage) -> Vec<bool> {
+    mask.to_luma8()
+        .pixels()
+        .map(|pixel| pixel.0[0] > 0)
+        .collect()
+}
+
+/// Loads an image, turning it upright according to its EXIF
+/// orientation, so photos taken with the camera rotated line up with
+/// the others. Images without an orientation are left as they are.
+pub fn load_oriented(path: &str) -> ImageResult<image::DynamicImage> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = image::DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Loads an image, keeping only its greyscale brightness.
+///
+/// The normal map only uses brightness, so this holds a third of the
+/// memory of a color image while producing the same normals, up to
+/// rounding the brightness to 8 bits. Color is
+/// dropped as soon as each image is decoded, so albedo generated from
+/// these images will be greyscale. Float images are also reduced to
+/// 8 bits, losing brightness above 1.
+pub fn load_grayscale(path: &str) -> ImageResult<image::DynamicImage> {
+    let image = load_oriented(path)?;
+    Ok(image::DynamicImage::ImageLuma8(image.into_luma8()))
 }
 
 impl RadianceMap {
+    /// Whether a pixel was masked out of this map (see alpha_mask)
+    pub fn is_masked(&self, pixel: usize) -> bool {
+        self.mask.as_ref().is_some_and(|mask| !mask[pixel])
+    }
     /// Load a radiance map from a file
     pub fn load(path: &str) -> ImageResult<Self> {
-        let image = ImageReader::open(path)?.decode()?;
+        let image = load_oriented(path)?;
         Ok(RadianceMap::from(image))
     }
+    /// Loads radiance maps for a set of images, along with the
+    /// direction of the light for each image from a sidecar file (see
+    /// light_utils::load_light_directions), kept next to the images.
+    /// There must be one direction for each image, in the same order.
+    pub fn load_with_lights(image_paths: &[&str], lights_path: &str) -> Result<Vec<Self>, String> {
+        let light_directions = crate::light_utils::load_light_directions(lights_path)?;
+        if light_directions.len() != image_paths.len() {
+            return Err(format!(
+                "{} has {} light directions, but there are {} images",
+                lights_path,
+                light_directions.len(),
+                image_paths.len()
+            ));
+        }
+        image_paths
+            .iter()
+            .zip(light_directions)
+            .map(|(path, light_direction)| {
+                let mut radiance_map = RadianceMap::load(path)
+                    .map_err(|err| format!("Could not load image {}: {}", path, err))?;
+                radiance_map.lighting_direction = light_direction;
+                Ok(radiance_map)
+            })
+            .collect()
+    }
     pub fn load_rgb_seed(path: &str, seed: i32) -> ImageResult<Self> {
-        let image = ImageReader::open(path)?.decode()?;
+        let image = load_oriented(path)?;
         let light_direction = Vector3::new(
             f32::cos(seed as f32) * 0.01,
             f32::sin(seed as f32) * 0.01,
@@ -53,3 +248,416 @@ impl RadianceMap {
         Ok(result)
     }
 }
+
+/// Finds the scale that brings each mean brightness to the average
+/// of all of them. Zero means are left unscaled.
+pub fn mean_matching_scales(means: &[f32]) -> Vec<f32> {
+    let target = means.iter().sum::<f32>() / means.len() as f32;
+    means
+        .iter()
+        .map(|mean| if *mean > 0.0 { target / mean } else { 1.0 })
+        .collect()
+}
+
+/// Scales each radiance map so they all have the same mean brightness,
+/// compensating for differences in exposure or light intensity.
+pub fn balance_radiances(radiance_maps: &mut [RadianceMap]) {
+    let means: Vec<f32> = radiance_maps
+        .iter()
+        .map(|radiance_map| radiance_map.radiance.mean())
+        .collect();
+    for (radiance_map, scale) in radiance_maps.iter_mut().zip(mean_matching_scales(&means)) {
+        radiance_map.radiance *= scale;
+    }
+}
+
+/// Subtracts the minimum brightness of each pixel across all the
+/// radiance maps from that pixel in every map.
+///
+/// Brightness present under every light, like ambient light or a
+/// broad specular sheen, isn't explained by diffuse shading. When each
+/// pixel is shadowed from at least one light, its minimum is exactly
+/// that baseline, leaving cleaner diffuse shading behind.
+pub fn subtract_minimum(radiance_maps: &mut [RadianceMap]) {
+    let Some(first) = radiance_maps.first() else {
+        return;
+    };
+    let mut minimum = first.radiance.clone();
+    for radiance_map in radiance_maps.iter() {
+        minimum = minimum.inf(&radiance_map.radiance);
+    }
+    for radiance_map in radiance_maps.iter_mut() {
+        radiance_map.radiance -= &minimum;
+    }
+}
+
+/// Evens out a brightness gradient across a radiance map, such as from
+/// a nearby light, by dividing it by the bilinear interpolation of its
+/// corner brightnesses. As with albedo_utils::corner_weights, each
+/// quadrant's brightness is weighted towards its corner.
+pub fn corner_flatten_radiance(radiance_map: &mut RadianceMap) {
+    let (width, height) = (radiance_map.size[0], radiance_map.size[1]);
+    let (half_width, half_height) = (width / 2, height / 2);
+    if half_width == 0 || half_height == 0 {
+        return;
+    }
+    // upper left, upper right, lower left, lower right
+    let mut corners = [0.0f32; 4];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        let (x0, y0) = (half_width * (i % 2), half_height * (i / 2));
+        for y in 0..half_height {
+            for x in 0..half_width {
+                let dx = if i % 2 == 0 { x } else { half_width - 1 - x };
+                let dy = if i < 2 { y } else { half_height - 1 - y };
+                let radiance = radiance_map.radiance[(y0 + y) * width + x0 + x];
+                *corner += (dx + dy) as f32 * radiance;
+            }
+        }
+    }
+    let average = corners.iter().sum::<f32>() / 4.0;
+    if average <= 0.0 {
+        return;
+    }
+    let [upper_left, upper_right, lower_left, lower_right] = corners.map(|c| c / average);
+    for y in 0..height {
+        for x in 0..width {
+            let f_x = x as f32 / width as f32;
+            let f_y = y as f32 / height as f32;
+            let relative_intensity = (upper_left * (1. - f_x) + upper_right * f_x) * (1. - f_y)
+                + (lower_left * (1. - f_x) + lower_right * f_x) * f_y;
+            if relative_intensity > 0.0 {
+                radiance_map.radiance[y * width + x] /= relative_intensity;
+            }
+        }
+    }
+}
+
+/// Finds the brightness scale that best matches a new radiance map to
+/// a reference set, as the least squares fit of the new radiance to
+/// the mean of the references. Only pixels that are neither black nor
+/// saturated in any of the maps are compared. Returns 1 if there are
+/// no such pixels.
+pub fn best_exposure_scale(new: &RadianceMap, reference: &[RadianceMap]) -> f32 {
+    let usable = |radiance: f32| radiance > 0.0 && radiance < 0.99;
+    let (mut new_squared, mut new_reference) = (0.0, 0.0);
+    for (pixel, new_radiance) in new.radiance.iter().enumerate() {
+        if !usable(*new_radiance) || reference.iter().any(|map| !usable(map.radiance[pixel])) {
+            continue;
+        }
+        let reference_radiance =
+            reference.iter().map(|map| map.radiance[pixel]).sum::<f32>() / reference.len() as f32;
+        new_squared += new_radiance * new_radiance;
+        new_reference += new_radiance * reference_radiance;
+    }
+    if new_squared > 0.0 {
+        new_reference / new_squared
+    } else {
+        1.0
+    }
+}
+
+/// Checks whether a radiance map carries shading information, rather
+/// than being almost entirely black or blown out.
+pub fn is_usable(radiance_map: &RadianceMap) -> bool {
+    let informative = radiance_map
+        .radiance
+        .iter()
+        .filter(|radiance| **radiance > 0.01 && **radiance < 0.99)
+        .count();
+    informative as f32 > 0.1 * radiance_map.radiance.nrows() as f32
+}
+
+/// Estimates the standard deviation of the noise in a radiance map.
+///
+/// Each pixel is filtered by a Laplacian that cancels out smooth
+/// shading, leaving mostly noise. The median response is used, so
+/// sparse edges like silhouettes don't count as noise.
+pub fn estimate_noise(radiance_map: &RadianceMap) -> f32 {
+    const KERNEL: [[f32; 3]; 3] = [[1.0, -2.0, 1.0], [-2.0, 4.0, -2.0], [1.0, -2.0, 1.0]];
+    let (width, height) = (radiance_map.size[0], radiance_map.size[1]);
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let mut responses = Vec::<f32>::with_capacity((width - 2) * (height - 2));
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let mut response = 0.0;
+            for (dy, row) in KERNEL.iter().enumerate() {
+                for (dx, weight) in row.iter().enumerate() {
+                    response += weight * radiance_map.radiance[(y + dy - 1) * width + x + dx - 1];
+                }
+            }
+            responses.push(response.abs());
+        }
+    }
+    let middle = responses.len() / 2;
+    let median = *responses.select_nth_unstable_by(middle, f32::total_cmp).1;
+    // For Gaussian noise, the kernel's response has a standard deviation
+    // of 6 sigma, and its median absolute value is 0.6745 times that.
+    median / (0.6745 * 6.0)
+}
+
+/// Iteration counts suggested for a set of captures by recommend_iterations
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IterationRecommendation {
+    /// Mean estimated noise of the captures, from estimate_noise
+    pub noise: f32,
+    /// Suggested NormalMapConfig::refinement_iterations
+    pub refinement_iterations: usize,
+    /// Suggested NormalMapConfig::smoothing_iterations
+    pub smoothing_iterations: usize,
+}
+
+/// Recommends how many refinement and smoothing iterations to run,
+/// based on the estimated noise of the captures. Noisier captures get
+/// more smoothing, and clean captures get none. This is only advice,
+/// to be copied into a NormalMapConfig if wanted.
+pub fn recommend_iterations(radiance_maps: &[RadianceMap]) -> IterationRecommendation {
+    let noise =
+        radiance_maps.iter().map(estimate_noise).sum::<f32>() / radiance_maps.len().max(1) as f32;
+    // One smoothing pass per half a percent of noise, with 8-bit
+    // quantization noise (about 0.1%) needing none
+    let smoothing_iterations = ((noise / 0.005).floor() as usize).min(8);
+    // Noisy light estimates settle more slowly
+    let refinement_iterations = 4 + smoothing_iterations / 2;
+    IterationRecommendation {
+        noise,
+        refinement_iterations,
+        smoothing_iterations,
+    }
+}
+
+/// Camera exposure settings, read from EXIF metadata
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exposure {
+    pub iso: f32,
+    /// Exposure time in seconds
+    pub exposure_time: f32,
+}
+
+impl Exposure {
+    /// How bright the image is relative to the scene radiance,
+    /// up to a constant factor
+    pub fn gain(&self) -> f32 {
+        self.iso * self.exposure_time
+    }
+}
+
+/// Loads the exposure settings from an image file's EXIF metadata.
+/// Returns None if the image has no ISO or exposure time.
+pub fn load_exposure(path: &str) -> ImageResult<Option<Exposure>> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    Ok(decoder
+        .exif_metadata()?
+        .and_then(|exif| parse_exif_exposure(&exif)))
+}
+
+/// Reads the ISO and exposure time from a raw EXIF (TIFF) chunk
+pub fn parse_exif_exposure(exif: &[u8]) -> Option<Exposure> {
+    let little_endian = match exif.get(0..4)? {
+        [0x49, 0x49, 42, 0] => true,
+        [0x4d, 0x4d, 0, 42] => false,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| {
+        let bytes: [u8; 2] = exif.get(offset..offset + 2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| {
+        let bytes: [u8; 4] = exif.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+    // Finds the value field offset of a tag in the directory at an offset
+    let find_tag = |directory: usize, tag: u16| {
+        let entries = read_u16(directory)? as usize;
+        (0..entries)
+            .map(|i| directory + 2 + i * 12)
+            .find(|entry| read_u16(*entry) == Some(tag))
+            .map(|entry| entry + 8)
+    };
+
+    let first_directory = read_u32(4)? as usize;
+    let exif_directory = read_u32(find_tag(first_directory, 0x8769)?)? as usize;
+    let iso = read_u16(find_tag(exif_directory, 0x8827)?)? as f32;
+    let exposure_time_offset = read_u32(find_tag(exif_directory, 0x829a)?)? as usize;
+    let numerator = read_u32(exposure_time_offset)? as f32;
+    let denominator = read_u32(exposure_time_offset + 4)? as f32;
+    if iso <= 0.0 || numerator <= 0.0 || denominator <= 0.0 {
+        return None;
+    }
+    Some(Exposure {
+        iso,
+        exposure_time: numerator / denominator,
+    })
+}
+
+/// Scales each radiance map by the inverse of its exposure gain, so
+/// they share the same scale. If any exposure is unknown, this falls
+/// back to matching their mean brightness with balance_radiances.
+pub fn apply_exposures(radiance_maps: &mut [RadianceMap], exposures: &[Option<Exposure>]) {
+    let gains: Option<Vec<f32>> = exposures
+        .iter()
+        .map(|exposure| exposure.map(|exposure| exposure.gain()))
+        .collect();
+    match gains {
+        Some(gains) if gains.len() == radiance_maps.len() => {
+            let mean_gain = gains.iter().sum::<f32>() / gains.len() as f32;
+            for (radiance_map, gain) in radiance_maps.iter_mut().zip(gains) {
+                radiance_map.radiance *= mean_gain / gain;
+            }
+        }
+        _ => balance_radiances(radiance_maps),
+    }
+}
+
+/// Recovers the radiance under each individual light from radiance
+/// maps captured under combinations (multiplexing) of the lights.
+///
+/// Row i of the demultiplexing matrix holds the weights of each
+/// captured map that sum to the radiance under light i, so for
+/// captures made with a multiplexing matrix M, this is the inverse
+/// (or pseudo-inverse) of M. The recovered maps light along the z
+/// axis until their lighting directions are set or estimated.
+pub fn demultiplex(
+    radiance_maps: &[RadianceMap],
+    demultiplexing: &DMatrix<f32>,
+) -> Result<Vec<RadianceMap>, String> {
+    if radiance_maps.is_empty() {
+        return Err("No radiance maps provided".to_string());
+    }
+    if demultiplexing.ncols() != radiance_maps.len() {
+        return Err(format!(
+            "Demultiplexing matrix has {} columns, but there are {} radiance maps",
+            demultiplexing.ncols(),
+            radiance_maps.len()
+        ));
+    }
+    let size = radiance_maps[0].size;
+    let mask = combined_mask(radiance_maps);
+    Ok(demultiplexing
+        .row_iter()
+        .map(|weights| {
+            let mut radiance = RadianceMatrix::zeros(size.product());
+            for (radiance_map, weight) in radiance_maps.iter().zip(weights.iter()) {
+                radiance += &radiance_map.radiance * *weight;
+            }
+            RadianceMap {
+                lighting_direction: Vector3::<f32>::z(),
+                size,
+                radiance,
+                mask: mask.clone(),
+            }
+        })
+        .collect())
+}
+
+/// Decodes an sRGB encoded value in [0, 1] to linear brightness, with
+/// the standard piecewise curve
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Encodes linear brightness in [0, 1] as sRGB, the inverse of
+/// srgb_to_linear
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Converts sRGB encoded radiance to linear radiance, which is what
+/// diffuse shading is proportional to
+pub fn linearize_srgb(radiance_maps: &mut [RadianceMap]) {
+    for radiance_map in radiance_maps.iter_mut() {
+        radiance_map
+            .radiance
+            .apply(|value| *value = srgb_to_linear(*value));
+    }
+}
+
+/// Decodes each color channel of an sRGB image to linear brightness,
+/// keeping alpha as it is. The result is a float image, which
+/// RadianceMap::from reads without further conversion. Unlike
+/// linearize_srgb, this decodes the channels before they are weighted
+/// into greyscale, as the sRGB curve applies to each channel.
+pub fn linearize_image(image: &image::DynamicImage) -> image::DynamicImage {
+    let mut linear = image.to_rgba32f();
+    for pixel in linear.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = srgb_to_linear(*channel);
+        }
+    }
+    linear.into()
+}
+
+/// A step of a Preprocessing pipeline
+#[derive(Clone, Debug, PartialEq)]
+pub enum PreprocessStep {
+    /// Converts from sRGB to linear radiance (see linearize_srgb)
+    Linearize,
+    /// Scales the maps to the same mean brightness (see
+    /// balance_radiances). After Linearize, this matches linear
+    /// brightness. Before it, the encoded brightness is matched, and
+    /// linearizing leaves the means unequal.
+    Balance,
+    /// Scales the maps by their exposures (see apply_exposures)
+    Exposures(Vec<Option<Exposure>>),
+    /// Evens out each map's brightness gradient (see
+    /// corner_flatten_radiance)
+    CornerFlatten,
+    /// Subtracts each pixel's minimum brightness (see subtract_minimum)
+    SubtractMinimum,
+}
+
+/// Preprocessing steps applied to the radiance maps in the order they
+/// were added, such as
+/// `Preprocessing::default().then(PreprocessStep::Linearize).then(PreprocessStep::Balance)`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preprocessing {
+    pub steps: Vec<PreprocessStep>,
+}
+
+impl Preprocessing {
+    /// Adds a step after the existing ones
+    pub fn then(mut self, step: PreprocessStep) -> Self {
+        self.steps.push(step);
+        self
+    }
+
+    /// Applies each step in order
+    pub fn apply(&self, radiance_maps: &mut [RadianceMap]) -> Result<(), String> {
+        for step in &self.steps {
+            match step {
+                PreprocessStep::Linearize => linearize_srgb(radiance_maps),
+                PreprocessStep::Balance => balance_radiances(radiance_maps),
+                PreprocessStep::Exposures(exposures) => {
+                    if exposures.len() != radiance_maps.len() {
+                        return Err("Exposures don't match the number of images".to_string());
+                    }
+                    apply_exposures(radiance_maps, exposures);
+                }
+                PreprocessStep::CornerFlatten => {
+                    radiance_maps.iter_mut().for_each(corner_flatten_radiance)
+                }
+                PreprocessStep::SubtractMinimum => subtract_minimum(radiance_maps),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use na::Vector3;
+use rayon::prelude::*;
+
+use crate::normal_utils::*;
+use crate::radiance_map::*;
+
+/// A model of how a surface reflects light, which can be fit to the
+/// brightness of a single pixel under several known lights.
+pub trait ReflectanceModel: Sync {
+    /// Finds the normal that best explains the brightness under each
+    /// light, along with the rms residual of the fit.
+    /// Returns None if the fit is underconstrained.
+    ///
+    /// Each row of `light_directions` is the direction of the light
+    /// that produced the matching row of `radiances`.
+    fn fit(
+        &self,
+        light_directions: &NormalMatrix,
+        radiances: &RadianceMatrix,
+    ) -> Option<(Vector3<f32>, f32)>;
+}
+
+/// Matte surfaces, following phong diffuse shading
+pub struct Lambertian;
+
+impl ReflectanceModel for Lambertian {
+    fn fit(
+        &self,
+        light_directions: &NormalMatrix,
+        radiances: &RadianceMatrix,
+    ) -> Option<(Vector3<f32>, f32)> {
+        let scaled_normal = least_squares(light_directions, radiances)?;
+        let residual = radiances - light_directions * scaled_normal;
+        let rms = (residual.norm_squared() / radiances.nrows() as f32).sqrt();
+        Some((scaled_normal.try_normalize(0.0)?, rms))
+    }
+}
+
+/// Glossy surfaces, following phong diffuse shading plus a
+/// Blinn-Phong specular lobe of the given shininess, seen by a camera
+/// looking along the z axis.
+///
+/// The normal is fit to the diffuse shading after dropping the
+/// observation that is brightest relative to the diffuse fit, then the
+/// specular intensity is fit to what the diffuse shading leaves over.
+pub struct SpecularAware {
+    pub shininess: f32,
+}
+
+impl Default for SpecularAware {
+    fn default() -> Self {
+        Self { shininess: 20.0 }
+    }
+}
+
+impl ReflectanceModel for SpecularAware {
+    fn fit(
+        &self,
+        light_directions: &NormalMatrix,
+        radiances: &RadianceMatrix,
+    ) -> Option<(Vector3<f32>, f32)> {
+        // Dropping a row still needs to leave enough to solve
+        if radiances.nrows() < 4 {
+            return None;
+        }
+        let scaled_normal = least_squares(light_directions, radiances)?;
+        let residual = radiances - light_directions * scaled_normal;
+        let brightest = residual.imax();
+        let diffuse_lights = light_directions.clone().remove_row(brightest);
+        let diffuse_radiances = radiances.clone().remove_row(brightest);
+        let scaled_normal = least_squares(&diffuse_lights, &diffuse_radiances)?;
+        let normal = scaled_normal.try_normalize(0.0)?;
+
+        // Fit the specular intensity to the remaining brightness
+        let remaining = radiances - light_directions * scaled_normal;
+        let specular = RadianceMatrix::from_iterator(
+            radiances.nrows(),
+            light_directions.row_iter().map(|light| {
+                let light = Vector3::new(light[0], light[1], light[2]);
+                let halfway = (light + Vector3::z()).normalize();
+                normal.dot(&halfway).max(0.0).powf(self.shininess)
+            }),
+        );
+        let specular_squared = specular.norm_squared();
+        let intensity = if specular_squared > 0.0 {
+            (remaining.dot(&specular) / specular_squared).max(0.0)
+        } else {
+            0.0
+        };
+        let residual = remaining - specular * intensity;
+        let rms = (residual.norm_squared() / radiances.nrows() as f32).sqrt();
+        Some((normal, rms))
+    }
+}
+
+/// Estimates the normal of each pixel by fitting every model, and
+/// keeping whichever fits best. Returns the normals along with the
+/// index of the model chosen for each pixel, which classifies each
+/// pixel's material.
+///
+/// Models should be listed from simplest to most complex. A later
+/// model is only chosen if it lowers the rms residual by more than
+/// `min_improvement`, so exact fits aren't decided by rounding error.
+/// Pixels no model can fit face forwards, with a classification of
+/// `models.len()`.
+pub fn generate_normals_with_models(
+    radiance_maps: &[RadianceMap],
+    models: &[&dyn ReflectanceModel],
+    min_improvement: f32,
+) -> (NormalMatrix, Vec<usize>) {
+    let mut light_directions = Vec::<f32>::new();
+    for radiance_map in radiance_maps {
+        light_directions.extend_from_slice(radiance_map.lighting_direction.as_slice());
+    }
+    let light_directions = NormalMatrix::from_row_slice(&light_directions);
+
+    let fits: Vec<(Vector3<f32>, usize)> = (0..radiance_maps[0].size.product())
+        .into_par_iter()
+        .map(|pixel| {
+            let radiances = RadianceMatrix::from_iterator(
+                radiance_maps.len(),
+                radiance_maps
+                    .iter()
+                    .map(|radiance_map| radiance_map.radiance[pixel]),
+            );
+            let mut best: Option<(Vector3<f32>, f32, usize)> = None;
+            for (i, model) in models.iter().enumerate() {
+                if let Some((normal, rms)) = model.fit(&light_directions, &radiances) {
+                    if best.is_none_or(|(_, best_rms, _)| rms < best_rms - min_improvement) {
+                        best = Some((normal, rms, i));
+                    }
+                }
+            }
+            best.map_or((Vector3::z(), models.len()), |(normal, _, i)| (normal, i))
+        })
+        .collect();
+
+    let mut normals = Vec::<f32>::new();
+    for (normal, _) in &fits {
+        normals.extend_from_slice(normal.as_slice());
+    }
+    let materials = fits.into_iter().map(|(_, material)| material).collect();
+    (NormalMatrix::from_row_slice(&normals), materials)
+}
+
+/// Separates each radiance map into diffuse and specular components.
+///
+/// Specular highlights only ever add brightness, so for each pixel,
+/// the `specular_count` brightest observations are treated as possibly
+/// specular. Diffuse shading is fit to the remaining observations, and
+/// anything brighter than that fit is moved into the specular component.
+/// Returns the diffuse radiance maps, then the specular radiance maps.
+pub fn separate_specular(
+    radiance_maps: &[RadianceMap],
+    specular_count: usize,
+) -> (Vec<RadianceMap>, Vec<RadianceMap>) {
+    let specular: Vec<Vec<f32>> = (0..radiance_maps[0].size.product())
+        .into_par_iter()
+        .map(|pixel| {
+            let mut order: Vec<usize> = (0..radiance_maps.len()).collect();
+            order.sort_by(|a, b| {
+                radiance_maps[*a].radiance[pixel].total_cmp(&radiance_maps[*b].radiance[pixel])
+            });
+            let diffuse_count = radiance_maps.len().saturating_sub(specular_count);
+            let mut light_directions: Vec<f32> = Vec::new();
+            let mut radiances: Vec<f32> = Vec::new();
+            for i in &order[..diffuse_count] {
+                light_directions.extend_from_slice(radiance_maps[*i].lighting_direction.as_slice());
+                radiances.push(radiance_maps[*i].radiance[pixel]);
+            }
+            let scaled_normal = if diffuse_count < 3 {
+                None
+            } else {
+                least_squares(
+                    &NormalMatrix::from_row_slice(&light_directions),
+                    &RadianceMatrix::from_row_slice(&radiances),
+                )
+            };
+            radiance_maps
+                .iter()
+                .map(|radiance_map| match scaled_normal {
+                    None => 0.0,
+                    Some(scaled_normal) => {
+                        let predicted =
+                            scaled_normal.dot(&radiance_map.lighting_direction).max(0.0);
+                        (radiance_map.radiance[pixel] - predicted).max(0.0)
+                    }
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut diffuse_maps = Vec::<RadianceMap>::new();
+    let mut specular_maps = Vec::<RadianceMap>::new();
+    for (i, radiance_map) in radiance_maps.iter().enumerate() {
+        let specular_radiance =
+            RadianceMatrix::from_iterator(specular.len(), specular.iter().map(|pixel| pixel[i]));
+        diffuse_maps.push(RadianceMap {
+            lighting_direction: radiance_map.lighting_direction,
+            size: radiance_map.size,
+            radiance: &radiance_map.radiance - &specular_radiance,
+            mask: radiance_map.mask.clone(),
+        });
+        specular_maps.push(RadianceMap {
+            lighting_direction: radiance_map.lighting_direction,
+            size: radiance_map.size,
+            radiance: specular_radiance,
+            mask: radiance_map.mask.clone(),
+        });
+    }
+    (diffuse_maps, specular_maps)
+}
@@ -0,0 +1,89 @@
+use image::{DynamicImage, GrayImage};
+use na::{DMatrix, Vector3};
+
+use crate::normal_utils::*;
+use crate::radiance_map::*;
+
+/// Finds how much brighter each pixel is than predicted by diffuse
+/// shading of the given normals and albedo, for each radiance map.
+///
+/// The result is an n x m matrix, where n is the pixel count, and m is
+/// the number of radiance maps.
+pub fn reconstruction_residuals(
+    normals: &NormalMatrix,
+    albedo: &RadianceMatrix,
+    radiance_maps: &[RadianceMap],
+) -> DMatrix<f32> {
+    DMatrix::from_fn(normals.nrows(), radiance_maps.len(), |pixel, i| {
+        let normal = Vector3::new(
+            normals[(pixel, 0)],
+            normals[(pixel, 1)],
+            normals[(pixel, 2)],
+        );
+        let radiance_map = &radiance_maps[i];
+        let predicted = albedo[pixel] * normal.dot(&radiance_map.lighting_direction).max(0.0);
+        radiance_map.radiance[pixel] - predicted
+    })
+}
+
+/// Flags pixels that are likely lit by interreflections.
+///
+/// Interreflected light only ever adds brightness, so a pixel that is
+/// brighter than predicted by more than `threshold` under at least
+/// `fraction` of the lights is flagged.
+pub fn detect_interreflections(
+    residuals: &DMatrix<f32>,
+    threshold: f32,
+    fraction: f32,
+) -> Vec<bool> {
+    residuals
+        .row_iter()
+        .map(|row| {
+            let brighter = row.iter().filter(|residual| **residual > threshold).count();
+            brighter as f32 >= fraction * row.len() as f32
+        })
+        .collect()
+}
+
+/// Estimates how well each pixel fits the diffuse shading model, from 1
+/// (a perfect fit) down to 0 (an rms residual of 1 or more).
+/// Flagged pixels, such as detected interreflections, are given 0.
+pub fn confidence_map(residuals: &DMatrix<f32>, flagged: &[bool]) -> RadianceMatrix {
+    let confidence: Vec<f32> = residuals
+        .row_iter()
+        .zip(flagged)
+        .map(|(row, flagged)| {
+            if *flagged {
+                return 0.0;
+            }
+            let rms = (row.norm_squared() / row.len() as f32).sqrt();
+            (1.0 - rms).max(0.0)
+        })
+        .collect();
+    RadianceMatrix::from_row_slice(&confidence)
+}
+
+/// Renders the absolute residual of each radiance map (see
+/// reconstruction_residuals) as a greyscale image, so it's easy to see
+/// where each light fits worst. A residual of 1 or more is white.
+pub fn per_light_residual_images(
+    normals: &NormalMatrix,
+    albedo: &RadianceMatrix,
+    radiance_maps: &[RadianceMap],
+) -> Vec<DynamicImage> {
+    let residuals = reconstruction_residuals(normals, albedo, radiance_maps);
+    radiance_maps
+        .iter()
+        .zip(residuals.column_iter())
+        .map(|(radiance_map, residuals)| {
+            let bytes: Vec<u8> = residuals
+                .iter()
+                .map(|residual| (residual.abs().min(1.0) * 255.0).round() as u8)
+                .collect();
+            let size = radiance_map.size;
+            GrayImage::from_vec(size[0] as u32, size[1] as u32, bytes)
+                .expect("Residuals should match the radiance map size")
+                .into()
+        })
+        .collect()
+}
@@ -0,0 +1,279 @@
+mod common;
+
+use common::*;
+use image::{DynamicImage, GenericImage, GenericImageView, ImageReader, Rgba, RgbaImage};
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::albedo_utils::*;
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+use normals_from_shading::*;
+
+#[test]
+fn solved_albedo_is_shading_free() {
+    let width = 64;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.6; size.product()];
+    let radiance_maps: Vec<_> = ring_lights(4)
+        .into_iter()
+        .map(|light| render(&normals, &albedo, size, light))
+        .collect();
+
+    // Only compare pixels lit by every light, since shadows aren't diffuse
+    let lit: Vec<usize> = (0..size.product())
+        .filter(|&i| covered[i] && radiance_maps.iter().all(|map| map.radiance[i] > 0.0))
+        .collect();
+
+    let solved = solve_albedo(&radiance_maps);
+    let solved_variance = variance(lit.iter().map(|&i| solved[i]));
+    let solved_mean = lit.iter().map(|&i| solved[i]).sum::<f32>() / lit.len() as f32;
+    assert!(
+        (solved_mean - 0.6).abs() < 1e-3,
+        "mean albedo {}",
+        solved_mean
+    );
+    assert!(
+        solved_variance < 1e-6,
+        "solved variance {}",
+        solved_variance
+    );
+
+    // The plain average still carries the sphere's shading
+    let averaged = lit.iter().map(|&i| {
+        radiance_maps.iter().map(|map| map.radiance[i]).sum::<f32>() / radiance_maps.len() as f32
+    });
+    assert!(variance(averaged) > 100.0 * solved_variance);
+}
+
+#[test]
+fn tiled_albedo_matches_albedo() {
+    let images: Vec<DynamicImage> = [
+        "sample_input/tile_512_a.jpg",
+        "sample_input/tile_512_b.jpg",
+        "sample_input/tile_512_c.jpg",
+    ]
+    .iter()
+    .map(|path| ImageReader::open(path).unwrap().decode().unwrap())
+    .collect();
+
+    let albedo = generate_albedo(&images).unwrap();
+    let tiled_albedo = generate_albedo_tiled(&images, 100).unwrap();
+    assert_eq!(albedo.dimensions(), tiled_albedo.dimensions());
+
+    let bytes = albedo.to_rgba8().into_raw();
+    let tiled_bytes = tiled_albedo.to_rgba8().into_raw();
+    let mean_difference = bytes
+        .iter()
+        .zip(&tiled_bytes)
+        .map(|(a, b)| (*a as f32 - *b as f32).abs())
+        .sum::<f32>()
+        / bytes.len() as f32;
+    assert!(mean_difference < 2.0, "mean difference {}", mean_difference);
+}
+
+#[test]
+fn median_albedo_rejects_outliers() {
+    let mut images: Vec<DynamicImage> = (0..3)
+        .map(|_| RgbaImage::from_pixel(16, 16, Rgba([100, 100, 100, 255])).into())
+        .collect();
+    // Add a lens flare to one image
+    for x in 6..10 {
+        for y in 6..10 {
+            images[1].put_pixel(x, y, Rgba([250, 250, 250, 255]));
+        }
+    }
+
+    let spot_brightness = |reduction: Reduce| {
+        let config = AlbedoConfig {
+            reduction,
+            ..Default::default()
+        };
+        let albedo = generate_albedo_with_config(&images, &config).unwrap();
+        albedo.get_pixel(8, 8).0[0] as i32 - albedo.get_pixel(2, 8).0[0] as i32
+    };
+    assert!(spot_brightness(Reduce::Median).abs() <= 1);
+    assert!(spot_brightness(Reduce::Mean) > 20);
+}
+
+#[test]
+fn tv_denoising_keeps_edges() {
+    // Two flat halves with a sharp edge between them, plus noise
+    let mut state = 5u64;
+    let noisy = RgbaImage::from_fn(32, 32, |x, _| {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        let noise = ((state >> 56) as i32 - 128) / 8;
+        let base = if x < 16 { 60 } else { 190 };
+        let value = (base + noise).clamp(0, 255) as u8;
+        Rgba([value, value, value, 255])
+    });
+    let noisy = DynamicImage::from(noisy);
+    let denoised = tv_denoise(&noisy, 0.1);
+
+    let half_variance = |image: &DynamicImage, columns: std::ops::Range<u32>| {
+        let values: Vec<f32> = columns
+            .flat_map(|x| (0..32).map(move |y| (x, y)))
+            .map(|(x, y)| image.get_pixel(x, y).0[0] as f32)
+            .collect();
+        variance(values.into_iter())
+    };
+    assert!(half_variance(&denoised, 0..14) < half_variance(&noisy, 0..14) / 4.0);
+    assert!(half_variance(&denoised, 18..32) < half_variance(&noisy, 18..32) / 4.0);
+    // The edge stays sharp
+    for y in 0..32 {
+        let step = denoised.get_pixel(16, y).0[0] as i32 - denoised.get_pixel(15, y).0[0] as i32;
+        assert!(step > 90, "row {} step {}", y, step);
+    }
+    assert_eq!(denoised.get_pixel(3, 3).0[3], 255);
+}
+
+#[test]
+fn rgb_albedo_flattens_each_channel() {
+    // A red surface, lit with a blue cast on the right, and a plain
+    // white light on the left
+    let lit = DynamicImage::from(image::RgbImage::from_fn(32, 32, |x, _| {
+        let cast = x as f32 / 31.0;
+        image::Rgb([
+            (200.0 * (1.0 - 0.4 * cast)) as u8,
+            (60.0 * (1.0 - 0.4 * cast)) as u8,
+            60,
+        ])
+    }));
+    let images = vec![lit.clone(), lit];
+
+    let albedo = generate_albedo_rgb(&images).unwrap();
+    assert_eq!(albedo.color(), image::ColorType::Rgb8);
+    let albedo = albedo.to_rgb8();
+    let (left, right) = (albedo.get_pixel(2, 16).0, albedo.get_pixel(29, 16).0);
+    // Each channel is evened out on its own
+    assert!(
+        (left[0] as i32 - right[0] as i32).abs() < 20,
+        "{:?} {:?}",
+        left,
+        right
+    );
+    assert!((left[2] as i32 - right[2] as i32).abs() < 5);
+    assert!(left[0] as u32 > 2 * left[1] as u32);
+
+    // Alpha is kept, and averaged rather than divided into the colors
+    let translucent: Vec<DynamicImage> = [100, 200]
+        .map(|alpha| DynamicImage::from(RgbaImage::from_pixel(8, 8, Rgba([90, 90, 90, alpha]))))
+        .to_vec();
+    let albedo = generate_albedo_rgb(&translucent).unwrap();
+    assert_eq!(albedo.color(), image::ColorType::Rgba8);
+    assert_eq!(albedo.get_pixel(4, 4).0, [90, 90, 90, 150]);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn disk_average_matches_memory_average() {
+    let images: Vec<DynamicImage> = (0..5u32)
+        .map(|seed| {
+            RgbaImage::from_fn(37, 23, |x, y| {
+                let value = (x * 7 + y * 13 + seed * 31) % 256;
+                Rgba([value as u8, (255 - value) as u8, (value / 2) as u8, 255])
+            })
+            .into()
+        })
+        .collect();
+    let path = std::env::temp_dir().join("normals_from_shading_disk_average.bin");
+    let mut disk = DiskAverage::new(path.to_str().unwrap(), 37, 23).unwrap();
+    assert!(disk.finish().is_none());
+    for image in &images {
+        disk.add(image).unwrap();
+    }
+    assert!(disk.add(&RgbaImage::new(4, 4).into()).is_err());
+    let disk_average = disk.finish().unwrap();
+    // The file can't be removed on some platforms while it's mapped
+    drop(disk);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        disk_average.as_bytes(),
+        average(&images).unwrap().as_bytes()
+    );
+}
+
+#[test]
+fn average_keeps_color_and_averages_alpha() {
+    let images: Vec<DynamicImage> = [Rgba([200, 10, 40, 255]), Rgba([100, 30, 0, 55])]
+        .into_iter()
+        .map(|pixel| RgbaImage::from_pixel(3, 2, pixel).into())
+        .collect();
+    let averaged = average(&images).unwrap().to_rgba8();
+    assert!(averaged.pixels().all(|pixel| pixel.0 == [150, 20, 20, 155]));
+}
+
+#[test]
+fn weighted_average_excludes_shadowed_observations() {
+    let (width, height) = (4, 4);
+    let normals = NormalMatrix::from_fn(width * height, |_, c| if c == 2 { 1.0 } else { 0.0 });
+    let radiance_maps: Vec<RadianceMap> = [0.4, 0.6, 0.5]
+        .into_iter()
+        .enumerate()
+        .map(|(i, brightness)| RadianceMap {
+            lighting_direction: Vector3::new(i as f32 - 1.0, 0.0, 1.0).normalize(),
+            size: Vector2::new(width, height),
+            // The last image has its left half in shadow
+            radiance: RadianceMatrix::from_fn(width * height, |pixel, _| {
+                if i == 2 && pixel % width < width / 2 {
+                    0.0
+                } else {
+                    brightness
+                }
+            }),
+            mask: None,
+        })
+        .collect();
+    let images: Vec<DynamicImage> = radiance_maps.iter().map(radiance_image).collect();
+
+    let weights = observation_weights(&normals, &radiance_maps, &SolveOptions::default());
+    let weighted = weighted_average(&images, &weights).unwrap().to_rgba8();
+    let plain = average(&images).unwrap().to_rgba8();
+    let unshadowed = average(&images[..2]).unwrap().to_rgba8();
+
+    // Shadowed pixels only average the images that saw them
+    assert_eq!(weighted.get_pixel(0, 1), unshadowed.get_pixel(0, 1));
+    assert!(plain.get_pixel(0, 1).0[0] < weighted.get_pixel(0, 1).0[0]);
+    // Everywhere else, every image counts
+    assert_eq!(weighted.get_pixel(3, 1), plain.get_pixel(3, 1));
+    assert!(weighted_average(&images, &weights[..2]).is_none());
+}
+
+#[test]
+fn average_rounds_and_handles_large_stacks() {
+    let gray = Rgba([128, 128, 128, 255]);
+    let images: Vec<DynamicImage> = (0..256)
+        .map(|_| RgbaImage::from_pixel(4, 4, gray).into())
+        .collect();
+    let averaged = average(&images).unwrap().to_rgba8();
+    assert!(averaged.pixels().all(|pixel| *pixel == gray));
+
+    // 100 and 101 average to 100.5, which rounds up
+    let images: Vec<DynamicImage> = [100, 101]
+        .into_iter()
+        .map(|value| RgbaImage::from_pixel(2, 2, Rgba([value, value, value, 255])).into())
+        .collect();
+    assert_eq!(
+        average(&images).unwrap().to_rgba8().get_pixel(0, 0).0,
+        [101, 101, 101, 255]
+    );
+
+    assert!(average(&[]).is_none());
+}
+
+#[test]
+fn transparent_pixels_have_no_albedo() {
+    let images: Vec<DynamicImage> = (0..3)
+        .map(|_| {
+            let mut image = RgbaImage::from_pixel(8, 8, Rgba([120, 90, 60, 255]));
+            image.put_pixel(2, 3, Rgba([120, 90, 60, 0]));
+            image.into()
+        })
+        .collect();
+    let albedo = generate_albedo(&images).unwrap();
+    assert_eq!(albedo.get_pixel(2, 3).0, [0, 0, 0, 0]);
+    assert!(albedo.get_pixel(5, 5).0[..3]
+        .iter()
+        .all(|channel| *channel > 0));
+}
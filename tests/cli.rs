@@ -0,0 +1,121 @@
+mod common;
+
+use common::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Writes rendered sphere images to a fresh directory for the CLI
+fn write_sphere_images(name: &str) -> (PathBuf, Vec<PathBuf>) {
+    let directory = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    let mut paths = Vec::new();
+    for (i, image) in sphere_images(32, &ring_lights(4)).iter().enumerate() {
+        let path = directory.join(format!("light_{}.png", i));
+        image.save(&path).unwrap();
+        paths.push(path);
+    }
+    (directory, paths)
+}
+
+fn run<I, S>(directory: &Path, args: I) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    Command::new(env!("CARGO_BIN_EXE_normals_from_shading"))
+        .args(args)
+        .current_dir(directory)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn cli_summary_reports_iterations_and_quality() {
+    let (directory, paths) = write_sphere_images("normals_from_shading_cli");
+    let output = run(&directory, &paths);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let summary = stdout
+        .lines()
+        .find(|line| line.starts_with("Refined for"))
+        .unwrap_or_else(|| panic!("No summary in:\n{}", stdout));
+    assert!(summary.contains("4 iterations"), "{}", summary);
+    let quality: f32 = summary.rsplit("quality ").next().unwrap().parse().unwrap();
+    assert!((0.0..=1.0).contains(&quality), "{}", summary);
+    assert!(directory.join("normal_map.png").exists());
+    assert!(directory.join("albedo.png").exists());
+
+    // Progress goes to stderr, leaving stdout for the results
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Refinement") && stderr.contains("Flatten"));
+    assert!(stderr.contains("[####################] 100%"));
+}
+
+#[test]
+fn cli_options_choose_outputs_and_iterations() {
+    let (directory, paths) = write_sphere_images("normals_from_shading_cli_options");
+    let mut args = vec![
+        "--normal-out".into(),
+        "normals.png".into(),
+        "--albedo-out".into(),
+        "color.png".into(),
+        "--iterations".into(),
+        "2".into(),
+    ];
+    args.extend(paths.iter().map(|path| path.clone().into_os_string()));
+    let output = run(&directory, &args);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Refined for 2 iterations"), "{}", stdout);
+    assert!(directory.join("normals.png").exists());
+    assert!(directory.join("color.png").exists());
+    assert!(!directory.join("normal_map.png").exists());
+}
+
+#[test]
+fn cli_rejects_invalid_arguments() {
+    let directory = std::env::temp_dir();
+    let help = run(&directory, ["--help"]);
+    assert!(help.status.success());
+    let usage = String::from_utf8(help.stdout).unwrap();
+    for option in ["--albedo-out", "--normal-out", "--iterations"] {
+        assert!(usage.contains(option), "{}", usage);
+    }
+
+    for args in [
+        vec!["--frobnicate", "a.png"],
+        vec!["--iterations", "many", "a.png"],
+        vec!["a.png", "--normal-out"],
+        vec!["--threads", "all", "a.png"],
+        vec![],
+    ] {
+        let output = run(&directory, &args);
+        assert!(!output.status.success(), "{:?} was accepted", args);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("Usage:"), "{:?}: {}", args, stderr);
+    }
+}
+
+#[test]
+fn cli_reports_failures_on_stderr() {
+    let (directory, mut paths) = write_sphere_images("normals_from_shading_cli_failures");
+
+    let missing = directory.join("missing.png");
+    let mut args = paths.clone();
+    args.push(missing);
+    let output = run(&directory, &args);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Could not load image"), "{}", stderr);
+    assert!(output.stdout.is_empty());
+
+    let small = directory.join("small.png");
+    sphere_images(16, &ring_lights(1))[0].save(&small).unwrap();
+    paths.push(small);
+    let output = run(&directory, &paths);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("different sizes"), "{}", stderr);
+    assert!(!directory.join("albedo.png").exists());
+}
@@ -0,0 +1,158 @@
+#![allow(dead_code)]
+
+use image::{DynamicImage, GrayImage};
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+
+/// Normals of a sphere filling a square image of the given width.
+/// Pixels outside the sphere face forwards, and are reported as
+/// false in the returned coverage.
+pub fn sphere_normals(width: usize) -> (NormalMatrix, Vec<bool>) {
+    let mut normals = Vec::<f32>::new();
+    let mut covered = Vec::<bool>::new();
+    let radius = width as f32 / 2.0;
+    for y in 0..width {
+        for x in 0..width {
+            let dx = (x as f32 + 0.5 - radius) / radius;
+            let dy = (y as f32 + 0.5 - radius) / radius;
+            let dz2 = 1.0 - dx * dx - dy * dy;
+            if dz2 > 0.0 {
+                normals.extend_from_slice(&[dx, dy, dz2.sqrt()]);
+                covered.push(true);
+            } else {
+                normals.extend_from_slice(&[0.0, 0.0, 1.0]);
+                covered.push(false);
+            }
+        }
+    }
+    (NormalMatrix::from_row_slice(&normals), covered)
+}
+
+/// Renders diffuse shading of a normal field with per-pixel albedo
+pub fn render(
+    normals: &NormalMatrix,
+    albedo: &[f32],
+    size: Vector2<usize>,
+    light: Vector3<f32>,
+) -> RadianceMap {
+    let light = light.normalize();
+    let radiance: Vec<f32> = (0..normals.nrows())
+        .map(|i| {
+            let normal = Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+            albedo[i] * normal.dot(&light).max(0.0)
+        })
+        .collect();
+    RadianceMap {
+        lighting_direction: light,
+        size,
+        radiance: RadianceMatrix::from_row_slice(&radiance),
+        mask: None,
+    }
+}
+
+/// A spread of lights tilted about 30 degrees from the camera axis
+pub fn ring_lights(count: usize) -> Vec<Vector3<f32>> {
+    (0..count)
+        .map(|i| {
+            let angle = i as f32 * std::f32::consts::TAU / count as f32;
+            Vector3::new(angle.cos() * 0.5, angle.sin() * 0.5, 1.0).normalize()
+        })
+        .collect()
+}
+
+/// Variance of the selected values
+pub fn variance(values: impl Iterator<Item = f32> + Clone) -> f32 {
+    let count = values.clone().count() as f32;
+    let mean = values.clone().sum::<f32>() / count;
+    values.map(|v| (v - mean).powi(2)).sum::<f32>() / count
+}
+
+/// Converts a radiance map into an 8 bit greyscale image
+pub fn radiance_image(radiance_map: &RadianceMap) -> DynamicImage {
+    let bytes: Vec<u8> = radiance_map
+        .radiance
+        .iter()
+        .map(|radiance| (radiance * 255.0).round().clamp(0.0, 255.0) as u8)
+        .collect();
+    let size = radiance_map.size;
+    GrayImage::from_vec(size[0] as u32, size[1] as u32, bytes)
+        .expect("Radiance should match its size")
+        .into()
+}
+
+/// Renders a sphere of uniform albedo as seen under each light
+pub fn sphere_images(width: usize, lights: &[Vector3<f32>]) -> Vec<DynamicImage> {
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.8; size.product()];
+    lights
+        .iter()
+        .map(|light| radiance_image(&render(&normals, &albedo, size, *light)))
+        .collect()
+}
+
+/// Normals of the height field h(x, y), found from its gradient
+pub fn height_field_normals(
+    size: Vector2<usize>,
+    gradient: impl Fn(f32, f32) -> (f32, f32),
+) -> NormalMatrix {
+    let mut normals = Vec::<f32>::new();
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let (dx, dy) = gradient(x as f32, y as f32);
+            normals.extend_from_slice(Vector3::new(-dx, -dy, 1.0).normalize().as_slice());
+        }
+    }
+    NormalMatrix::from_row_slice(&normals)
+}
+
+/// Pseudo-random unit normals facing the camera
+pub fn random_normals(count: usize, seed: u64) -> NormalMatrix {
+    let mut state = seed;
+    let mut random = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 40) as f32 / (1u64 << 24) as f32
+    };
+    let mut normals = Vec::<f32>::new();
+    for _ in 0..count {
+        let normal = Vector3::new(random() - 0.5, random() - 0.5, 0.5 + random());
+        normals.extend_from_slice(normal.normalize().as_slice());
+    }
+    NormalMatrix::from_row_slice(&normals)
+}
+
+/// Mean angle in degrees between matching normals of the selected pixels
+pub fn mean_angle_error(a: &NormalMatrix, b: &NormalMatrix, pixels: &[usize]) -> f32 {
+    pixels
+        .iter()
+        .map(|&i| {
+            let dot = a.row(i).dot(&b.row(i)).clamp(-1.0, 1.0);
+            dot.acos().to_degrees()
+        })
+        .sum::<f32>()
+        / pixels.len() as f32
+}
+
+/// Adds a Blinn-Phong highlight, seen from the camera, to a radiance map
+pub fn add_highlight(
+    radiance_map: &mut RadianceMap,
+    normals: &NormalMatrix,
+    intensity: f32,
+    shininess: f32,
+) {
+    let halfway = (radiance_map.lighting_direction + Vector3::z()).normalize();
+    for i in 0..normals.nrows() {
+        let normal = Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+        radiance_map.radiance[i] += intensity * normal.dot(&halfway).max(0.0).powf(shininess);
+    }
+}
+
+/// Pixels inside the sphere that are lit by every radiance map
+pub fn lit_pixels(covered: &[bool], radiance_maps: &[RadianceMap]) -> Vec<usize> {
+    (0..covered.len())
+        .filter(|&i| covered[i] && radiance_maps.iter().all(|map| map.radiance[i] > 0.0))
+        .collect()
+}
@@ -0,0 +1,83 @@
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+
+#[test]
+fn edge_flatten_interpolates_each_axis() {
+    let size = Vector2::new(24, 16);
+    // Normals that tilt further down the image, but don't vary across it
+    let mut rows = Vec::<f32>::new();
+    for y in 0..size[1] {
+        for _ in 0..size[0] {
+            let tilt = y as f32 / size[1] as f32 - 0.5;
+            rows.extend_from_slice(Vector3::new(0.0, tilt, 1.0).normalize().as_slice());
+        }
+    }
+    let normals = NormalMatrix::from_row_slice(&rows);
+    let flattened = edge_flatten(&normals, &size);
+
+    let tilt_sum = |normals: &NormalMatrix| normals.column(1).abs().sum();
+    assert!(tilt_sum(&flattened) < 0.6 * tilt_sum(&normals));
+    // The result still only varies down the image
+    for y in 0..size[1] {
+        let first = flattened.row(y * size[0]);
+        for x in 1..size[0] {
+            let difference = (flattened.row(y * size[0] + x) - first).norm();
+            assert!(
+                difference < 1e-5,
+                "({}, {}) differs by {}",
+                x,
+                y,
+                difference
+            );
+        }
+    }
+}
+
+#[test]
+fn corner_flatten_handles_tall_images() {
+    let size = Vector2::new(64, 96);
+    let tilted = Vector3::new(0.3, -0.2, 1.0).normalize();
+    let normals = NormalMatrix::from_row_slice(&tilted.as_slice().repeat(size.product()));
+    let flattened = corner_flatten(&normals, &size);
+    for normal in flattened.row_iter() {
+        assert!((normal.transpose() - Vector3::z()).norm() < 1e-4);
+    }
+
+    // An evenly lit albedo stays even
+    let grey = image::GrayImage::from_pixel(64, 96, image::Luma([100]));
+    let albedo = normals_from_shading::albedo_utils::corner_flatten(&grey.into());
+    assert!(albedo.to_luma8().pixels().all(|pixel| pixel.0[0] == 100));
+}
+
+#[test]
+fn combined_flatten_removes_linear_and_bilinear_tilt() {
+    let size = Vector2::new(48, 32);
+    // A tilt that grows across and down the image, and twists with both
+    let mut rows = Vec::<f32>::new();
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let f_x = x as f32 / size[0] as f32 - 0.5;
+            let f_y = y as f32 / size[1] as f32 - 0.5;
+            let tilt = Vector3::new(0.6 * f_x + 1.2 * f_x * f_y, 0.5 * f_y, 1.0);
+            rows.extend_from_slice(tilt.normalize().as_slice());
+        }
+    }
+    let normals = NormalMatrix::from_row_slice(&rows);
+    let remaining_tilt = |normals: &NormalMatrix| {
+        normals
+            .row_iter()
+            .map(|normal| normal.transpose().angle(&Vector3::z()).to_degrees())
+            .sum::<f32>()
+            / normals.nrows() as f32
+    };
+    let edge = remaining_tilt(&edge_flatten(&normals, &size));
+    let corner = remaining_tilt(&corner_flatten(&normals, &size));
+    let combined = remaining_tilt(&combined_flatten(&normals, &size));
+    assert!(
+        combined < 0.5 * edge.min(corner),
+        "combined {} vs edge {} and corner {}",
+        combined,
+        edge,
+        corner
+    );
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use common::*;
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+use std::f32::consts::PI;
+
+/// Gradient of h(x, y) = 3 sin(x / 8) cos(y / 10)
+fn bumps(x: f32, y: f32) -> (f32, f32) {
+    (
+        3.0 / 8.0 * (x / 8.0).cos() * (y / 10.0).cos(),
+        -3.0 / 10.0 * (x / 8.0).sin() * (y / 10.0).sin(),
+    )
+}
+
+#[test]
+fn height_field_is_integrable() {
+    let size = Vector2::new(64, 48);
+    let normals = height_field_normals(size, bumps);
+    let error = integrability_error(&normals, &size);
+    assert!(error < 0.005, "height field error {}", error);
+
+    let random = random_normals(size.product(), 7);
+    let random_error = integrability_error(&random, &size);
+    assert!(random_error > 0.2, "random field error {}", random_error);
+}
+
+#[test]
+fn enforcing_integrability_removes_noise() {
+    let size = Vector2::new(64, 48);
+    let clean = height_field_normals(size, bumps);
+    let noise = random_normals(size.product(), 11);
+    let mut noisy = clean.clone() + noise * 0.2;
+    for mut normal in noisy.row_iter_mut() {
+        normal.normalize_mut();
+    }
+
+    let enforced = enforce_integrability(&noisy, &size);
+    let noisy_error = integrability_error(&noisy, &size);
+    let enforced_error = integrability_error(&enforced, &size);
+    assert!(
+        enforced_error < 0.1 * noisy_error,
+        "error went from {} to {}",
+        noisy_error,
+        enforced_error
+    );
+
+    // The enforced field should stay close to the input
+    let mean_alignment = noisy
+        .row_iter()
+        .zip(enforced.row_iter())
+        .map(|(a, b)| a.dot(&b))
+        .sum::<f32>()
+        / size.product() as f32;
+    assert!(mean_alignment > 0.98, "mean alignment {}", mean_alignment);
+}
+
+#[test]
+fn frankot_chellappa_recovers_heights() {
+    let size = Vector2::new(64, 48);
+    let (fx, fy) = (2.0 * PI / 64.0, 2.0 * PI / 48.0);
+    // h(x, y) = 3 sin(fx x) cos(fy y), which is periodic over the image
+    let normals = height_field_normals(size, |x, y| {
+        (
+            3.0 * fx * (fx * x).cos() * (fy * y).cos(),
+            -3.0 * fy * (fx * x).sin() * (fy * y).sin(),
+        )
+    });
+    let heights = integrate_heights(&normals, &size);
+    assert_eq!(heights.shape(), (48, 64));
+    assert_eq!((heights.min(), heights.max()), (0.0, 1.0));
+    for y in 0..size[1] {
+        for x in 0..size[0] {
+            let expected = 0.5 + 0.5 * (fx * x as f32).sin() * (fy * y as f32).cos();
+            let error = (heights[(y, x)] - expected).abs();
+            assert!(error < 0.02, "error {} at ({}, {})", error, x, y);
+        }
+    }
+}
+
+#[test]
+fn frankot_chellappa_handles_horizon_normals() {
+    let size = Vector2::new(8, 6);
+    let mut normals = random_normals(size.product(), 3);
+    for pixel in [0, 9, 20] {
+        normals.set_row(pixel, &Vector3::new(1.0, 0.0, 0.0).transpose());
+    }
+    let heights = integrate_heights(&normals, &size);
+    assert!(heights.iter().all(|height| height.is_finite()));
+}
+
+#[test]
+fn frankot_chellappa_scales_to_large_maps() {
+    // Neither side is a power of two, so both take the general path
+    let size = Vector2::new(600, 500);
+    let (fx, fy) = (2.0 * PI / 600.0, 4.0 * PI / 500.0);
+    let normals = height_field_normals(size, |x, y| {
+        (
+            40.0 * fx * (fx * x).cos() * (fy * y).cos(),
+            -40.0 * fy * (fx * x).sin() * (fy * y).sin(),
+        )
+    });
+    let start = std::time::Instant::now();
+    let heights = integrate_heights(&normals, &size);
+    let elapsed = start.elapsed();
+    // A direct DFT takes minutes at this size without optimizations
+    assert!(elapsed.as_secs_f32() < 20.0, "took {:?}", elapsed);
+    for (y, x) in [(0, 0), (125, 150), (250, 300), (499, 599)] {
+        let expected = 0.5 + 0.5 * (fx * x as f32).sin() * (fy * y as f32).cos();
+        let error = (heights[(y, x)] - expected).abs();
+        assert!(error < 0.02, "error {} at ({}, {})", error, x, y);
+    }
+}
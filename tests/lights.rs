@@ -0,0 +1,227 @@
+mod common;
+
+use common::*;
+use nalgebra::Vector2;
+use normals_from_shading::light_utils::*;
+use normals_from_shading::normal_utils::*;
+
+#[test]
+fn estimated_lights_match_known_lights() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.7; size.product()];
+    let lights = ring_lights(5);
+
+    let estimated: Vec<_> = lights
+        .iter()
+        .map(|light| {
+            let radiance_map = render(&normals, &albedo, size, *light);
+            generate_lighting_direction(&normals, &radiance_map.radiance).unwrap()
+        })
+        .collect();
+    let errors = light_angular_errors(&estimated, &lights).unwrap();
+    assert_eq!(errors.len(), 5);
+    for error in errors {
+        assert!(error < 2.0, "light error {} degrees", error);
+    }
+    assert!(light_angular_errors(&estimated, &lights[1..]).is_err());
+}
+
+#[test]
+fn radiance_band_ignores_shadows_and_saturation() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let pixels: Vec<usize> = (0..size.product()).filter(|i| covered[*i]).collect();
+    let normals = normals.select_rows(&pixels);
+    let albedo = vec![1.6; pixels.len()];
+    // Steep lights leave part of the sphere in shadow, and the bright
+    // albedo blows out the rest
+    let lights: Vec<_> = ring_lights(4)
+        .iter()
+        .map(|light| nalgebra::Vector3::new(light.x * 3.0, light.y * 3.0, light.z))
+        .collect();
+    let mut plain = Vec::new();
+    let mut banded = Vec::new();
+    for light in &lights {
+        let radiance = render(&normals, &albedo, Vector2::new(pixels.len(), 1), *light)
+            .radiance
+            .map(|value| value.min(1.0));
+        plain.push(generate_lighting_direction(&normals, &radiance).unwrap());
+        banded.push(generate_lighting_direction_in_band(&normals, &radiance, 0.02, 0.98).unwrap());
+    }
+    let plain_errors = light_angular_errors(&plain, &lights).unwrap();
+    let banded_errors = light_angular_errors(&banded, &lights).unwrap();
+    for (plain_error, banded_error) in plain_errors.iter().zip(&banded_errors) {
+        assert!(*banded_error < 2.0, "banded error {} degrees", banded_error);
+        assert!(
+            plain_error > banded_error,
+            "{} vs {}",
+            plain_error,
+            banded_error
+        );
+    }
+
+    // Too few pixels in the band falls back to using all of them
+    let saturated = nalgebra::DVector::from_element(pixels.len(), 1.0);
+    assert_eq!(
+        generate_lighting_direction_in_band(&normals, &saturated, 0.02, 0.98),
+        generate_lighting_direction(&normals, &saturated)
+    );
+}
+
+#[test]
+fn underconstrained_lights_are_errors() {
+    let flat = NormalMatrix::from_row_slice(&[0.0, 0.0, 1.0].repeat(10));
+    let radiance = nalgebra::DVector::from_element(10, 0.5);
+    assert!(generate_lighting_direction(&flat, &radiance).is_err());
+
+    let (normals, _) = sphere_normals(16);
+    let black = nalgebra::DVector::zeros(normals.nrows());
+    assert!(generate_lighting_direction(&normals, &black).is_err());
+
+    // A one pixel image can't constrain the lights, so the solve fails
+    // rather than panicking
+    let pixels: Vec<image::DynamicImage> = [[200u8], [120], [60]]
+        .into_iter()
+        .map(|value| {
+            image::GrayImage::from_raw(1, 1, value.to_vec())
+                .unwrap()
+                .into()
+        })
+        .collect();
+    assert!(normals_from_shading::generate_normal_map(&pixels).is_err());
+}
+
+#[test]
+fn yaml_light_directions_load() {
+    let path = std::env::temp_dir().join("normals_from_shading_lights.yml");
+    let yaml = "# Calibrated rig\nlights:\n  - [0, 0, 1]\n  - [3.0, 0.0, 4.0]  # unnormalized\n  - [-0.6, 0.8, 0.0]\n";
+    std::fs::write(&path, yaml).unwrap();
+    let lights = load_light_directions_yaml(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let expected = [
+        nalgebra::Vector3::new(0.0, 0.0, 1.0),
+        nalgebra::Vector3::new(0.6, 0.0, 0.8),
+        nalgebra::Vector3::new(-0.6, 0.8, 0.0),
+    ];
+    assert_eq!(lights.len(), 3);
+    for (light, expected) in lights.iter().zip(expected) {
+        assert!((light - expected).norm() < 1e-6);
+    }
+    assert!(parse_light_directions_yaml("- [1, 0]\n- [0, 1, 0]\n- [0, 0, 1]\n").is_err());
+}
+
+#[test]
+fn near_duplicate_lights_cluster_together() {
+    let directions = [
+        nalgebra::Vector3::new(0.5, 0.0, 1.0).normalize(),
+        nalgebra::Vector3::new(-0.5, 0.2, 1.0).normalize(),
+        nalgebra::Vector3::new(0.51, 0.01, 1.0).normalize(),
+    ];
+    let clusters = cluster_lights(&directions, 2.0);
+    assert_eq!(clusters, vec![vec![0, 2], vec![1]]);
+    assert_eq!(cluster_lights(&directions, 90.0), vec![vec![0, 1, 2]]);
+}
+
+#[test]
+fn sidecar_lights_are_assigned_to_images() {
+    let directory = std::env::temp_dir();
+    let images =
+        ["tile_512_a", "tile_512_b", "tile_512_c"].map(|name| format!("sample_input/{}.jpg", name));
+    let images: Vec<&str> = images.iter().map(String::as_str).collect();
+
+    let json_path = directory.join("normals_from_shading_lights.json");
+    std::fs::write(&json_path, "[[0, 0, 2], [3, 0, 4],\n [0, -1, 1]]").unwrap();
+    let json_path = json_path.to_str().unwrap();
+    let radiance_maps =
+        normals_from_shading::radiance_map::RadianceMap::load_with_lights(&images, json_path)
+            .unwrap();
+    assert_eq!(radiance_maps.len(), 3);
+    assert_eq!(radiance_maps[0].lighting_direction, nalgebra::Vector3::z());
+    assert!(
+        (radiance_maps[1].lighting_direction - nalgebra::Vector3::new(0.6, 0.0, 0.8)).norm() < 1e-6
+    );
+
+    let csv_path = directory.join("normals_from_shading_lights.csv");
+    std::fs::write(&csv_path, "x,y,z\n0,0,1\n1,0,1\n0,1,1\n-1,0,1\n").unwrap();
+    let error = normals_from_shading::radiance_map::RadianceMap::load_with_lights(
+        &images,
+        csv_path.to_str().unwrap(),
+    )
+    .err()
+    .unwrap();
+    assert!(
+        error.contains("4 light directions, but there are 3 images"),
+        "{}",
+        error
+    );
+    std::fs::remove_file(json_path).unwrap();
+    std::fs::remove_file(&csv_path).unwrap();
+
+    assert!(parse_light_directions_json("[[0, 0, 1], [1, 0]]").is_err());
+    assert!(parse_light_directions_json("[[0, 0, 0]]").is_err());
+    // Too few lights, like the CSV and YAML parsers
+    assert!(parse_light_directions_json("[]").is_err());
+    assert!(parse_light_directions_json("[[0, 0, 1], [1, 0, 1]]").is_err());
+}
+
+#[test]
+fn normal_map_result_reports_lights() {
+    let lights = ring_lights(4);
+    let images = sphere_images(40, &lights);
+    let result = normals_from_shading::generate_normal_map_result(
+        &images,
+        &normals_from_shading::NormalMapConfig::default(),
+    )
+    .unwrap();
+    let errors = light_angular_errors(&result.light_directions, &lights).unwrap();
+    // Each light is reported in the order of its image
+    for error in errors {
+        assert!(error < 15.0, "light error {} degrees", error);
+    }
+}
+
+#[test]
+fn light_selection_prefers_spread_directions() {
+    let mut directions = ring_lights(4);
+    // Near duplicates of the first light
+    directions.insert(
+        1,
+        (directions[0] + nalgebra::Vector3::new(0.0, 0.01, 0.0)).normalize(),
+    );
+    directions.push((directions[0] + nalgebra::Vector3::new(0.01, 0.0, 0.0)).normalize());
+
+    let selected = select_lights(&directions, 4);
+    assert_eq!(selected.len(), 4);
+    for (n, a) in selected.iter().enumerate() {
+        for b in &selected[n + 1..] {
+            let angle = directions[*a].angle(&directions[*b]).to_degrees();
+            assert!(
+                angle > 5.0,
+                "lights {} and {} are {} degrees apart",
+                a,
+                b,
+                angle
+            );
+        }
+    }
+    assert_eq!(select_lights(&directions, 10).len(), directions.len());
+}
+
+#[test]
+fn calibration_sphere_is_detected() {
+    let (center, radius) = (nalgebra::Vector2::new(61.3f32, 44.8), 23.5f32);
+    let image = image::GrayImage::from_fn(120, 90, |x, y| {
+        let offset = nalgebra::Vector2::new(x as f32 + 0.5, y as f32 + 0.5) - center;
+        image::Luma([if offset.norm() <= radius { 230 } else { 20 }])
+    });
+    let (found_center, found_radius) = detect_sphere(&image.into()).unwrap();
+    assert!((found_center - center).norm() < 1.0, "{}", found_center);
+    assert!((found_radius - radius).abs() < 1.0, "{}", found_radius);
+
+    let blank = image::GrayImage::from_pixel(8, 8, image::Luma([90]));
+    assert!(detect_sphere(&blank.into()).is_none());
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use common::*;
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+use normals_from_shading::reflectance_model::*;
+
+#[test]
+fn glossy_pixels_are_classified() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.5; size.product()];
+    let is_glossy = |i: usize| i % width >= width / 2;
+
+    // Add a specular highlight to the right half of the sphere
+    let lights = ring_lights(8);
+    let highlight = |i: usize, light: &Vector3<f32>| {
+        let normal = Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+        let halfway = (light + Vector3::z()).normalize();
+        0.5 * normal.dot(&halfway).max(0.0).powf(20.0)
+    };
+    let radiance_maps: Vec<_> = lights
+        .iter()
+        .map(|light| {
+            let mut radiance_map = render(&normals, &albedo, size, *light);
+            for i in (0..size.product()).filter(|i| is_glossy(*i)) {
+                radiance_map.radiance[i] += highlight(i, light);
+            }
+            radiance_map
+        })
+        .collect();
+    // Only glossy pixels with a visible highlight can be told apart
+    let is_glossy = |i: usize| is_glossy(i) && lights.iter().any(|light| highlight(i, light) > 0.1);
+
+    let (_, materials) = generate_normals_with_models(
+        &radiance_maps,
+        &[&Lambertian, &SpecularAware::default()],
+        0.005,
+    );
+    let lit: Vec<usize> = (0..size.product())
+        .filter(|&i| covered[i] && radiance_maps.iter().all(|map| map.radiance[i] > 0.0))
+        .collect();
+    let glossy_correct = lit
+        .iter()
+        .filter(|&&i| is_glossy(i) && materials[i] == 1)
+        .count() as f32
+        / lit.iter().filter(|&&i| is_glossy(i)).count() as f32;
+    let matte_correct = lit
+        .iter()
+        .filter(|&&i| i % width < width / 2 && materials[i] == 0)
+        .count() as f32
+        / lit.iter().filter(|&&i| i % width < width / 2).count() as f32;
+    assert!(glossy_correct > 0.8, "glossy correct {}", glossy_correct);
+    assert!(matte_correct > 0.8, "matte correct {}", matte_correct);
+}
+
+#[test]
+fn separated_diffuse_improves_normals() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.5; size.product()];
+    let clean_maps: Vec<_> = ring_lights(8)
+        .into_iter()
+        .map(|light| render(&normals, &albedo, size, light))
+        .collect();
+    let lit = lit_pixels(&covered, &clean_maps);
+    let glossy_maps: Vec<_> = clean_maps
+        .into_iter()
+        .map(|mut radiance_map| {
+            add_highlight(&mut radiance_map, &normals, 0.5, 20.0);
+            radiance_map
+        })
+        .collect();
+
+    // Keep every observation, including the brightest highlights,
+    // and count them all equally
+    let keep_all = SolveOptions {
+        specular_threshold: f32::INFINITY,
+        shadow_threshold: f32::NEG_INFINITY,
+        confidence_weighting: false,
+        ..Default::default()
+    };
+    let (naive_normals, _) = generate_normals_with_options(&glossy_maps, &keep_all);
+    let naive_error = mean_angle_error(&naive_normals, &normals, &lit);
+    let (diffuse_maps, _) = separate_specular(&glossy_maps, 3);
+    let separated_error = mean_angle_error(&generate_normals(&diffuse_maps), &normals, &lit);
+    assert!(
+        separated_error < 0.5 * naive_error,
+        "naive error {}, separated error {}",
+        naive_error,
+        separated_error
+    );
+}
+
+#[test]
+fn minimum_subtraction_removes_baseline() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.5; size.product()];
+    // Low lights, so most pixels are shadowed from at least one
+    let lights: Vec<_> = ring_lights(8)
+        .into_iter()
+        .map(|light| Vector3::new(light.x, light.y, 0.1).normalize())
+        .collect();
+    let clean_maps: Vec<_> = lights
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    let mut sheen_maps: Vec<_> = clean_maps
+        .iter()
+        .map(|radiance_map| RadianceMap {
+            lighting_direction: radiance_map.lighting_direction,
+            size,
+            radiance: radiance_map.radiance.add_scalar(0.2),
+            mask: None,
+        })
+        .collect();
+
+    let sphere: Vec<usize> = (0..size.product()).filter(|i| covered[*i]).collect();
+    let clean_normals = generate_normals(&clean_maps);
+    let naive_error = mean_angle_error(&generate_normals(&sheen_maps), &clean_normals, &sphere);
+    subtract_minimum(&mut sheen_maps);
+    let subtracted_error =
+        mean_angle_error(&generate_normals(&sheen_maps), &clean_normals, &sphere);
+    assert!(
+        subtracted_error < 0.5 * naive_error,
+        "naive error {}, subtracted error {}",
+        naive_error,
+        subtracted_error
+    );
+}
@@ -0,0 +1,24 @@
+mod common;
+
+use common::*;
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+
+#[test]
+fn merge_favors_confident_normals() {
+    let confident = random_normals(50, 1);
+    let doubtful = random_normals(50, 2);
+    let merged = merge_normal_maps(&[
+        (confident.clone(), RadianceMatrix::from_element(50, 0.95)),
+        (doubtful.clone(), RadianceMatrix::from_element(50, 0.05)),
+    ]);
+    let pixels: Vec<usize> = (0..50).collect();
+    let to_confident = mean_angle_error(&merged, &confident, &pixels);
+    let to_doubtful = mean_angle_error(&merged, &doubtful, &pixels);
+    assert!(to_confident < 0.2 * to_doubtful);
+
+    // The merged normals are still unit length
+    for normal in merged.row_iter() {
+        assert!((normal.norm() - 1.0).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,543 @@
+mod common;
+
+use common::*;
+use normals_from_shading::*;
+
+#[test]
+fn single_thread_matches_parallel() {
+    let images = sphere_images(48, &ring_lights(4));
+    let parallel = generate_normal_map(&images).unwrap();
+    let config = NormalMapConfig {
+        threads: Some(1),
+        ..Default::default()
+    };
+    let single_thread = generate_normal_map_with_config(&images, &config).unwrap();
+    assert_eq!(parallel.as_bytes(), single_thread.as_bytes());
+}
+
+#[test]
+fn refinement_converges() {
+    let images = sphere_images(48, &ring_lights(4));
+    let result = generate_normal_map_result(&images, &NormalMapConfig::default()).unwrap();
+    let deltas = result.iteration_deltas;
+    assert_eq!(deltas.len(), 4);
+    for pair in deltas.windows(2) {
+        assert!(pair[1] < pair[0], "deltas didn't decrease: {:?}", deltas);
+    }
+}
+
+#[test]
+fn too_few_usable_images() {
+    let mut images = sphere_images(32, &ring_lights(3));
+    // A black frame carries no shading information
+    images[2] = image::GrayImage::new(32, 32).into();
+
+    let error = generate_normal_map(&images).unwrap_err();
+    assert!(error.contains("Only 2 usable images"), "{}", error);
+
+    let config = NormalMapConfig {
+        too_few_lights: TooFewLights::BrightnessGradient,
+        ..Default::default()
+    };
+    let normal_map = generate_normal_map_with_config(&images, &config).unwrap();
+    assert_eq!(normal_map.width(), 32);
+}
+
+#[test]
+fn per_image_crops_align_offset_captures() {
+    let images = sphere_images(48, &ring_lights(4));
+    // The subject shifts between shots within a larger frame
+    let offsets = [(0, 0), (5, 3), (12, 9), (2, 14)];
+    let mut captures = Vec::new();
+    let mut crops = Vec::new();
+    for (image, (x, y)) in images.iter().zip(offsets) {
+        let mut capture = image::GrayImage::new(64, 64);
+        image::imageops::replace(&mut capture, &image.to_luma8(), x, y);
+        captures.push(image::DynamicImage::from(capture));
+        crops.push(Rect {
+            x: x as u32,
+            y: y as u32,
+            width: 48,
+            height: 48,
+        });
+    }
+
+    let config = NormalMapConfig {
+        crops,
+        ..Default::default()
+    };
+    let aligned = generate_normal_map_with_config(&captures, &config).unwrap();
+    let expected = generate_normal_map(&images).unwrap();
+    assert_eq!(aligned.as_bytes(), expected.as_bytes());
+
+    let mut mismatched = config.crops.clone();
+    mismatched[1].width = 40;
+    assert!(crop_images(&captures, &mismatched).is_err());
+}
+
+#[test]
+fn solve_all_matches_normal_map() {
+    let lights = ring_lights(4);
+    let images = sphere_images(40, &lights);
+    let output = solve_all(&images, &NormalMapConfig::default()).unwrap();
+    let pixel_count = 40 * 40;
+    assert_eq!(output.size, nalgebra::Vector2::new(40, 40));
+    assert_eq!(output.normals.nrows(), pixel_count);
+    assert_eq!(output.albedo.shape(), (40, 40));
+    assert_eq!(output.confidence.nrows(), pixel_count);
+    assert_eq!(output.lights.len(), lights.len());
+
+    let normal_map = generate_normal_map(&images).unwrap();
+    let encoded =
+        output_utils::normals_to_image(&output.normals, &output.size, Default::default()).unwrap();
+    assert_eq!(encoded.as_bytes(), normal_map.as_bytes());
+    // The sphere's albedo is 0.8 at its center
+    assert!((output.albedo[(20, 20)] - 0.8).abs() < 0.05);
+    assert!(output.confidence[20 * 40 + 20] > 0.9);
+}
+
+#[test]
+fn confident_pixels_speed_up_light_estimation() {
+    let lights = ring_lights(4);
+    let mut images = sphere_images(48, &lights);
+    // A noisy cast shadow falls over part of the sphere under all but
+    // the first light, which diffuse shading can't explain
+    let mut state = 3u64;
+    for image in images.iter_mut().skip(1) {
+        let mut shadowed = image.to_luma8();
+        for y in 9..29 {
+            for x in 9..29 {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let noise = ((state >> 56) as i32 - 128) / 16;
+                let value = shadowed.get_pixel(x, y).0[0] as i32 / 5 + noise;
+                shadowed.put_pixel(x, y, image::Luma([value.clamp(0, 255) as u8]));
+            }
+        }
+        *image = shadowed.into();
+    }
+
+    // Number of refinement iterations needed before the mean light
+    // error drops below 13 degrees
+    let iterations_to_target = |light_confidence_threshold| {
+        (1..=6).find(|iterations| {
+            let config = NormalMapConfig {
+                refinement_iterations: *iterations,
+                light_confidence_threshold,
+                ..Default::default()
+            };
+            let output = solve_all(&images, &config).unwrap();
+            let errors = light_utils::light_angular_errors(&output.lights, &lights).unwrap();
+            errors.iter().sum::<f32>() / (errors.len() as f32) < 13.0
+        })
+    };
+    let restricted = iterations_to_target(Some(0.95));
+    let unrestricted = iterations_to_target(None);
+    assert!(restricted.is_some());
+    assert!(unrestricted.is_none_or(|unrestricted| restricted.unwrap() < unrestricted));
+}
+
+#[test]
+fn iteration_counts_are_configurable() {
+    let images = sphere_images(32, &ring_lights(4));
+    let default_result = generate_normal_map_result(&images, &NormalMapConfig::default()).unwrap();
+    assert_eq!(default_result.iteration_deltas.len(), 4);
+
+    let explicit = NormalMapConfig {
+        refinement_iterations: 4,
+        flatten_iterations: 10,
+        ..Default::default()
+    };
+    let explicit_map = generate_normal_map_with_config(&images, &explicit).unwrap();
+    assert_eq!(
+        explicit_map.as_bytes(),
+        default_result.normal_map.as_bytes()
+    );
+
+    let config = NormalMapConfig {
+        refinement_iterations: 2,
+        flatten_iterations: 0,
+        ..Default::default()
+    };
+    let result = generate_normal_map_result(&images, &config).unwrap();
+    assert_eq!(result.iteration_deltas.len(), 2);
+    assert_ne!(
+        result.normal_map.as_bytes(),
+        default_result.normal_map.as_bytes()
+    );
+}
+
+#[test]
+fn refinement_stops_once_lights_converge() {
+    let images = sphere_images(32, &ring_lights(4));
+    let capped = NormalMapConfig {
+        refinement_iterations: 20,
+        ..Default::default()
+    };
+    let converged = NormalMapConfig {
+        convergence_threshold: Some(0.5),
+        ..capped.clone()
+    };
+    let full = generate_normal_map_result(&images, &capped).unwrap();
+    let early = generate_normal_map_result(&images, &converged).unwrap();
+    assert_eq!(full.iterations(), 20);
+    assert!(early.iterations() >= 2 && early.iterations() < 20);
+    assert!(early.converged && !full.converged);
+    let (full_normals, _) = output_utils::image_to_normals(&full.normal_map);
+    let (early_normals, _) = output_utils::image_to_normals(&early.normal_map);
+    let pixels: Vec<usize> = (0..full_normals.nrows()).collect();
+    assert!(mean_angle_error(&full_normals, &early_normals, &pixels) < 2.0);
+}
+
+#[test]
+fn time_budget_stops_early() {
+    let images = sphere_images(32, &ring_lights(4));
+    let config = NormalMapConfig {
+        time_budget: Some(std::time::Duration::ZERO),
+        ..Default::default()
+    };
+    let result = generate_normal_map_result(&images, &config).unwrap();
+    assert!(result.time_limited);
+    // Only the first refinement iteration runs
+    assert_eq!(result.iteration_deltas.len(), 1);
+    assert_eq!(result.normal_map.width(), 32);
+
+    let unlimited = generate_normal_map_result(&images, &NormalMapConfig::default()).unwrap();
+    assert!(!unlimited.time_limited);
+}
+
+#[test]
+fn calibrated_lights_recover_sphere() {
+    let width = 40;
+    let lights = ring_lights(4);
+    let images = sphere_images(width, &lights);
+    // Unnormalized directions are fine
+    let scaled: Vec<_> = lights.iter().map(|light| light * 3.0).collect();
+    let normal_map = generate_normal_map_calibrated(&images, &scaled).unwrap();
+
+    let (normals, covered) = sphere_normals(width);
+    let radiance_maps: Vec<_> = images
+        .iter()
+        .map(|image| radiance_map::RadianceMap::from(image.clone()))
+        .collect();
+    let (decoded, _) = output_utils::image_to_normals(&normal_map);
+    let pixels = lit_pixels(&covered, &radiance_maps);
+    assert!(mean_angle_error(&decoded, &normals, &pixels) < 2.0);
+
+    let error = generate_normal_map_calibrated(&images, &lights[1..]).unwrap_err();
+    assert!(error.contains("Expected 3 images"), "{}", error);
+    let extra: Vec<_> = lights.iter().chain(&lights[..1]).copied().collect();
+    assert!(generate_normal_map_calibrated(&images, &extra).is_err());
+    // A zero light can't be normalized
+    let mut zeroed = lights.clone();
+    zeroed[0] = nalgebra::Vector3::zeros();
+    let error = generate_normal_map_calibrated(&images, &zeroed).unwrap_err();
+    assert!(error.contains("must not be zero"), "{}", error);
+}
+
+#[test]
+fn color_solve_beats_greyscale_on_colored_subjects() {
+    let width = 40;
+    let size = nalgebra::Vector2::new(width, width);
+    let lights = ring_lights(4);
+    let (normals, covered) = sphere_normals(width);
+    // A red subject, whose greyscale brightness is mostly the dim green
+    let channel_albedo = [0.9, 0.08, 0.05];
+    let mut state = 17u64;
+    let mut noise = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((state >> 40) as f32 / (1u64 << 24) as f32 - 0.5) * 0.04
+    };
+    let images: Vec<image::DynamicImage> = lights
+        .iter()
+        .map(|light| {
+            let shading = render(&normals, &vec![1.0; size.product()], size, *light);
+            let pixels: Vec<u8> = shading
+                .radiance
+                .iter()
+                .flat_map(|shade| channel_albedo.map(|albedo| albedo * shade))
+                .map(|value| ((value + noise()) * 255.0).round().clamp(0.0, 255.0) as u8)
+                .collect();
+            image::RgbImage::from_raw(width as u32, width as u32, pixels)
+                .unwrap()
+                .into()
+        })
+        .collect();
+
+    let radiance_maps: Vec<_> = lights
+        .iter()
+        .map(|light| render(&normals, &vec![1.0; size.product()], size, *light))
+        .collect();
+    let pixels: Vec<usize> = lit_pixels(&covered, &radiance_maps)
+        .into_iter()
+        .filter(|pixel| radiance_maps.iter().all(|map| map.radiance[*pixel] > 0.2))
+        .collect();
+    let error = |normal_map: image::DynamicImage| {
+        let (decoded, _) = output_utils::image_to_normals(&normal_map);
+        mean_angle_error(&decoded, &normals, &pixels)
+    };
+    let grey_error = error(generate_normal_map_calibrated(&images, &lights).unwrap());
+    let color_error = error(generate_normal_map_color_calibrated(&images, &lights).unwrap());
+    assert!(
+        color_error < 0.7 * grey_error,
+        "color error {} vs greyscale {}",
+        color_error,
+        grey_error
+    );
+    assert!(generate_normal_map_color_calibrated(&images, &lights[1..]).is_err());
+}
+
+#[test]
+fn color_key_masks_background() {
+    let width = 40;
+    let lights = ring_lights(4);
+    let (_, covered) = sphere_normals(width);
+    // The sphere in front of a magenta backdrop
+    let images: Vec<image::DynamicImage> = sphere_images(width, &lights)
+        .iter()
+        .map(|image| {
+            let grey = image.to_luma8();
+            image::RgbImage::from_fn(width as u32, width as u32, |x, y| {
+                if covered[y as usize * width + x as usize] {
+                    let value = grey.get_pixel(x, y).0[0];
+                    image::Rgb([value, value, value])
+                } else {
+                    image::Rgb([255, 0, 255])
+                }
+            })
+            .into()
+        })
+        .collect();
+    let color_key = radiance_map::ColorKey {
+        color: [255, 0, 255],
+        tolerance: 0.1,
+    };
+    let subject = radiance_map::color_key_mask(&images, &color_key);
+    assert_eq!(subject, covered);
+
+    let config = NormalMapConfig {
+        color_key: Some(color_key),
+        ..Default::default()
+    };
+    let normal_map = generate_normal_map_with_config(&images, &config)
+        .unwrap()
+        .to_rgb8();
+    let mut tilted = 0;
+    for (pixel, normal) in normal_map.pixels().enumerate() {
+        if covered[pixel] {
+            tilted += (normal.0 != [128, 128, 255]) as usize;
+        } else {
+            assert_eq!(normal.0, [128, 128, 255]);
+        }
+    }
+    // The subject is still solved
+    assert!(tilted > covered.iter().filter(|inside| **inside).count() / 2);
+}
+
+#[test]
+fn mask_image_skips_background() {
+    let width = 40;
+    let images = sphere_images(width, &ring_lights(4));
+    let (_, covered) = sphere_normals(width);
+    let mask = image::GrayImage::from_fn(width as u32, width as u32, |x, y| {
+        image::Luma([255 * covered[y as usize * width + x as usize] as u8])
+    });
+    let config = NormalMapConfig {
+        mask: Some(mask.into()),
+        ..Default::default()
+    };
+    let normal_map = generate_normal_map_with_config(&images, &config)
+        .unwrap()
+        .to_rgb8();
+    let mut tilted = 0;
+    for (pixel, normal) in normal_map.pixels().enumerate() {
+        if covered[pixel] {
+            tilted += (normal.0 != [128, 128, 255]) as usize;
+        } else {
+            assert_eq!(normal.0, [128, 128, 255]);
+        }
+    }
+    assert!(tilted > covered.iter().filter(|inside| **inside).count() / 2);
+
+    let wrong_size = NormalMapConfig {
+        mask: Some(image::GrayImage::new(width as u32, 8).into()),
+        ..Default::default()
+    };
+    assert!(generate_normal_map_with_config(&images, &wrong_size).is_err());
+}
+
+#[test]
+fn up_vector_rotates_into_object_frame() {
+    let images = sphere_images(40, &ring_lights(4));
+    let camera = solve_all(&images, &NormalMapConfig::default()).unwrap();
+    let angle = 20f32.to_radians();
+    let up = nalgebra::Vector3::new(0.0, angle.sin(), angle.cos());
+    let config = NormalMapConfig {
+        up_vector: Some(up),
+        ..Default::default()
+    };
+    let object = solve_all(&images, &config).unwrap();
+    // Every normal is turned by the rotation taking the up vector to +z
+    let rotation = nalgebra::Rotation3::rotation_between(&up, &nalgebra::Vector3::z()).unwrap();
+    for (before, after) in camera.normals.row_iter().zip(object.normals.row_iter()) {
+        let expected = rotation * before.transpose();
+        assert!((after.transpose() - expected).norm() < 1e-4);
+    }
+    // The sphere center faced the camera, so now leans by the tilt of up
+    let angle_to_z = object
+        .normals
+        .row(20 * 40 + 20)
+        .transpose()
+        .angle(&nalgebra::Vector3::z());
+    assert!(
+        (angle_to_z.to_degrees() - 20.0).abs() < 5.0,
+        "{}",
+        angle_to_z.to_degrees()
+    );
+
+    let zero = NormalMapConfig {
+        up_vector: Some(nalgebra::Vector3::zeros()),
+        ..Default::default()
+    };
+    assert!(solve_all(&images, &zero).is_err());
+}
+
+#[test]
+fn batch_rejects_bad_lights_and_sizes() {
+    let lights = ring_lights(4);
+    let images = sphere_images(32, &lights);
+    let mut mixed = images.clone();
+    mixed[2] = sphere_images(24, &lights).swap_remove(2);
+    let results = generate_batch(&[images.clone(), mixed], &lights);
+    assert!(results[0].is_ok());
+    assert!(results[1].as_ref().unwrap_err().contains("different sizes"));
+
+    // A zero light would otherwise spread NaN into every subject
+    let mut zeroed = lights.clone();
+    zeroed[1] = nalgebra::Vector3::zeros();
+    let results = generate_batch(&[images.clone(), images], &zeroed);
+    assert!(results
+        .iter()
+        .all(|result| result.as_ref().unwrap_err().contains("must not be zero")));
+}
+
+#[test]
+fn alpha_modes_add_opacity_and_masks() {
+    let width = 32;
+    let (_, covered) = sphere_normals(width);
+    let images: Vec<image::DynamicImage> = sphere_images(width, &ring_lights(4))
+        .iter()
+        .map(|image| {
+            let grey = image.to_luma8();
+            image::RgbImage::from_fn(width as u32, width as u32, |x, y| {
+                if covered[y as usize * width + x as usize] {
+                    let value = grey.get_pixel(x, y).0[0];
+                    image::Rgb([value, value, value])
+                } else {
+                    image::Rgb([0, 255, 0])
+                }
+            })
+            .into()
+        })
+        .collect();
+    let color_key = Some(radiance_map::ColorKey {
+        color: [0, 255, 0],
+        tolerance: 0.1,
+    });
+
+    let opaque = NormalMapConfig {
+        color_key,
+        output_alpha: output_utils::AlphaMode::Opaque,
+        ..Default::default()
+    };
+    let opaque_map = generate_normal_map_with_config(&images, &opaque).unwrap();
+    assert_eq!(opaque_map.color(), image::ColorType::Rgba8);
+    assert!(opaque_map
+        .to_rgba8()
+        .pixels()
+        .all(|pixel| pixel.0[3] == 255));
+
+    let masked = NormalMapConfig {
+        output_alpha: output_utils::AlphaMode::Mask,
+        ..opaque
+    };
+    let masked_map = generate_normal_map_with_config(&images, &masked).unwrap();
+    for (pixel, normal) in masked_map.to_rgba8().pixels().enumerate() {
+        assert_eq!(normal.0[3], if covered[pixel] { 255 } else { 0 });
+    }
+
+    let rgb = generate_normal_map(&images).unwrap();
+    assert_eq!(rgb.color(), image::ColorType::Rgb8);
+}
+
+#[test]
+fn linearizing_srgb_images_improves_normals() {
+    let width = 40;
+    let (normals, covered) = sphere_normals(width);
+    // Encode each image as sRGB, as a camera would
+    let images: Vec<image::DynamicImage> = sphere_images(width, &ring_lights(4))
+        .iter()
+        .map(|image| {
+            let mut encoded = image.to_luma8();
+            for pixel in encoded.pixels_mut() {
+                let linear = pixel.0[0] as f32 / 255.0;
+                pixel.0[0] = (radiance_map::linear_to_srgb(linear) * 255.0).round() as u8;
+            }
+            encoded.into()
+        })
+        .collect();
+    let pixels: Vec<usize> = (0..covered.len()).filter(|i| covered[*i]).collect();
+    let error = |linearize: bool| {
+        let config = NormalMapConfig {
+            linearize,
+            flatten_iterations: 0,
+            ..Default::default()
+        };
+        let normal_map = generate_normal_map_with_config(&images, &config).unwrap();
+        let (decoded, _) = output_utils::image_to_normals(&normal_map);
+        mean_angle_error(&decoded, &normals, &pixels)
+    };
+    let (encoded_error, linear_error) = (error(false), error(true));
+    assert!(
+        linear_error < 0.5 * encoded_error,
+        "encoded error {}, linearized error {}",
+        encoded_error,
+        linear_error
+    );
+}
+
+#[test]
+fn progress_is_reported_for_each_phase() {
+    let images = sphere_images(24, &ring_lights(4));
+    let events = std::sync::Mutex::new(Vec::new());
+    let config = NormalMapConfig::default();
+    let result = generate_normal_map_result_with_progress(&images, &config, |event| {
+        events.lock().unwrap().push(event)
+    })
+    .unwrap();
+    let events = events.into_inner().unwrap();
+    let phase = |phase: ProgressPhase| -> Vec<f32> {
+        events
+            .iter()
+            .filter(|event| event.phase == phase)
+            .map(|event| event.fraction)
+            .collect()
+    };
+    let refinement = [0.25, 0.5, 0.75, 1.0];
+    assert_eq!(phase(ProgressPhase::LightEstimation), refinement);
+    assert_eq!(phase(ProgressPhase::NormalSolve), refinement);
+    let flatten = phase(ProgressPhase::Flatten);
+    assert_eq!(flatten.len(), config.flatten_iterations);
+    assert_eq!(flatten.last(), Some(&1.0));
+    // Flattening comes after every refinement iteration
+    assert!(events[..8]
+        .iter()
+        .all(|event| event.phase != ProgressPhase::Flatten));
+
+    // Reporting progress doesn't change the result
+    let quiet = generate_normal_map_with_config(&images, &config).unwrap();
+    assert_eq!(result.normal_map.as_bytes(), quiet.as_bytes());
+}
@@ -0,0 +1,476 @@
+mod common;
+
+use common::*;
+use image::ImageFormat;
+use nalgebra::{Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::output_utils::*;
+use normals_from_shading::radiance_map::RadianceMatrix;
+
+#[test]
+fn streamed_normal_map_decodes() {
+    let size = Vector2::new(20, 12);
+    let normals = random_normals(size.product(), 3);
+    let mut png = Vec::<u8>::new();
+    write_normal_map(&normals, &size, NormalConvention::OpenGl, &mut png).unwrap();
+
+    let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png)
+        .unwrap()
+        .to_rgb8();
+    assert_eq!(decoded.dimensions(), (20, 12));
+    // OpenGL has y pointing up the image
+    let green = decoded.get_pixel(0, 0).0[1] as f32;
+    assert!((green - (128.0 - normals[(0, 1)] * 128.0)).abs() <= 1.0);
+    assert_eq!(
+        decoded.into_raw(),
+        normal_bytes(&normals, NormalConvention::OpenGl)
+    );
+    let wrong_size = Vector2::new(12, 20 + 1);
+    assert!(matches!(
+        write_normal_map(&normals, &wrong_size, NormalConvention::OpenGl, Vec::new()),
+        Err(image::ImageError::Parameter(_))
+    ));
+}
+
+#[test]
+fn snorm_encoding_is_symmetric() {
+    let normals = NormalMatrix::from_row_slice(&[0.0, 1.0, -1.0]);
+    let bytes = encode_normal_bytes(&normals, NormalConvention::DirectX, NormalEncoding::Snorm);
+    let values: Vec<i8> = bytes.iter().map(|byte| *byte as i8).collect();
+    assert_eq!(values, vec![0, 127, -127]);
+}
+
+#[test]
+fn thumbnail_keeps_normals_smooth() {
+    // A flat field stays uniformly flat
+    let size = Vector2::new(64, 32);
+    let mut flat = NormalMatrix::zeros(size.product());
+    flat.column_mut(2).fill(1.0);
+    let thumb = thumbnail_normals(&flat, &size, 16).to_rgb8();
+    assert_eq!(thumb.dimensions(), (16, 8));
+    assert!(thumb.pixels().all(|pixel| pixel.0 == [128, 128, 255]));
+
+    // A smooth tilt across the image stays smooth and monotonic
+    let size = Vector2::new(64, 64);
+    let mut gradient = NormalMatrix::zeros(size.product());
+    for (i, mut row) in gradient.row_iter_mut().enumerate() {
+        let x = (i % size[0]) as f32 / size[0] as f32 - 0.5;
+        let normal = nalgebra::Vector3::new(x, 0.0, 1.0).normalize();
+        row.copy_from(&normal.transpose());
+    }
+    let thumb = thumbnail_normals(&gradient, &size, 8).to_rgb8();
+    assert_eq!(thumb.dimensions(), (8, 8));
+    for y in 0..8 {
+        let reds: Vec<i32> = (0..8).map(|x| thumb.get_pixel(x, y).0[0] as i32).collect();
+        assert!(reds.windows(2).all(|w| w[1] > w[0] && w[1] - w[0] < 20));
+        assert_eq!(
+            reds,
+            (0..8)
+                .map(|x| thumb.get_pixel(x, 0).0[0] as i32)
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn normal_image_round_trips() {
+    let size = Vector2::new(16, 9);
+    let normals = random_normals(size.product(), 11);
+    let image = normals_to_image(&normals, &size, Default::default()).unwrap();
+    assert_eq!(image.as_bytes(), normal_bytes(&normals, Default::default()));
+
+    let (decoded, decoded_size) = image_to_normals(&image);
+    assert_eq!(decoded_size, size);
+    // Each component is quantized to 1/128
+    assert!((decoded - &normals).amax() < 2.0 / 128.0);
+    assert!(normals_to_image(&normals, &Vector2::new(4, 4), Default::default()).is_err());
+}
+
+#[test]
+fn normal_field_round_trips_exactly() {
+    let size = Vector2::new(7, 5);
+    let normals = random_normals(size.product(), 17);
+    let first = std::env::temp_dir().join("normals_from_shading_field_a.nfsn");
+    let second = std::env::temp_dir().join("normals_from_shading_field_b.nfsn");
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+    save_normal_field(&normals, &size, first).unwrap();
+    let (loaded, loaded_size) = load_normal_field(first).unwrap();
+    save_normal_field(&loaded, &loaded_size, second).unwrap();
+    let (first_bytes, second_bytes) = (
+        std::fs::read(first).unwrap(),
+        std::fs::read(second).unwrap(),
+    );
+    std::fs::remove_file(first).unwrap();
+    std::fs::remove_file(second).unwrap();
+
+    assert_eq!(loaded_size, size);
+    assert_eq!(loaded, normals);
+    assert_eq!(first_bytes, second_bytes);
+}
+
+#[test]
+fn corrupt_normal_fields_are_rejected() {
+    let size = Vector2::new(3, 2);
+    let normals = random_normals(size.product(), 19);
+    let path = std::env::temp_dir().join("normals_from_shading_field_corrupt.nfsn");
+    let path = path.to_str().unwrap();
+    save_normal_field(&normals, &size, path).unwrap();
+    let bytes = std::fs::read(path).unwrap();
+
+    let load = |data: &[u8]| {
+        std::fs::write(path, data).unwrap();
+        load_normal_field(path).map_err(|error| error.kind())
+    };
+    let invalid = Err(std::io::ErrorKind::InvalidData);
+    // Truncated in the header, and in the data
+    assert_eq!(load(&bytes[..20]).map(|_| ()), invalid);
+    assert_eq!(load(&bytes[..bytes.len() - 4]).map(|_| ()), invalid);
+    // Dimensions whose byte count overflows
+    let mut huge = bytes.clone();
+    huge[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+    huge[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_eq!(load(&huge).map(|_| ()), invalid);
+    // 12 * (2^62 + 6) wraps around to the 72 bytes this file really has
+    let mut wrapping = bytes.clone();
+    wrapping[8..16].copy_from_slice(&((1u64 << 62) + 6).to_le_bytes());
+    wrapping[16..24].copy_from_slice(&1u64.to_le_bytes());
+    assert_eq!(load(&wrapping).map(|_| ()), invalid);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn geometry_export_writes_normal_and_height() {
+    let size = Vector2::new(24, 16);
+    let normals = height_field_normals(size, |_, y| (0.05, 0.04 * y));
+    let base = std::env::temp_dir().join("normals_from_shading_geometry");
+    let base = base.to_str().unwrap();
+    export_geometry(&normals, &size, base, &GeometryExportConfig::default()).unwrap();
+
+    let normal_path = format!("{}_normal.png", base);
+    let height_path = format!("{}_height.png", base);
+    let normal_map = image::open(&normal_path).unwrap();
+    let height_map = image::open(&height_path).unwrap();
+    std::fs::remove_file(&normal_path).unwrap();
+    std::fs::remove_file(&height_path).unwrap();
+
+    assert_eq!((normal_map.width(), normal_map.height()), (24, 16));
+    assert_eq!(normal_map.color(), image::ColorType::Rgb8);
+    assert_eq!((height_map.width(), height_map.height()), (24, 16));
+    assert_eq!(height_map.color(), image::ColorType::L16);
+}
+
+#[test]
+fn height_image_stretches_small_ranges() {
+    // Heights spanning only a thousandth of a unit
+    let heights = nalgebra::DMatrix::from_fn(8, 10, |y, x| 5.0 + 0.0001 * (x + y) as f32);
+    let image = height_image(&heights).to_luma16();
+    let values: Vec<u16> = image.pixels().map(|pixel| pixel.0[0]).collect();
+    assert_eq!(values.iter().min(), Some(&0));
+    assert_eq!(values.iter().max(), Some(&65535));
+
+    // Gamma brightens the middle heights, keeping the ends in place
+    let gamma = height_image_with_scaling(&heights, HeightScaling::Gamma(2.2))
+        .unwrap()
+        .to_luma16();
+    assert_eq!(gamma.get_pixel(0, 0).0[0], 0);
+    assert_eq!(gamma.get_pixel(9, 7).0[0], 65535);
+    assert!(gamma.get_pixel(4, 4).0[0] > image.get_pixel(4, 4).0[0]);
+    for gamma in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+        assert!(height_image_with_scaling(&heights, HeightScaling::Gamma(gamma)).is_err());
+    }
+}
+
+#[test]
+fn sixteen_bit_normals_are_symmetric() {
+    let size = Vector2::new(3, 1);
+    let normals = NormalMatrix::from_row_slice(&[0.0, 0.0, 1.0, 1.0, 0.0, 0.0, -0.6, 0.0, 0.8]);
+    let image = normals_to_image_16bit(&normals, &size, NormalConvention::DirectX)
+        .unwrap()
+        .to_rgb16();
+    assert_eq!(image.get_pixel(0, 0).0, [32768, 32768, 65535]);
+    assert_eq!(image.get_pixel(1, 0).0, [65535, 32768, 32768]);
+    let mirrored = image.get_pixel(2, 0).0[0];
+    assert_eq!(
+        65536 - mirrored as u32,
+        (32768.0 + 0.6f32 * 32767.0).round() as u32
+    );
+
+    let images = sphere_images(24, &ring_lights(4));
+    let config = normals_from_shading::NormalMapConfig::default();
+    let normal_map = normals_from_shading::generate_normal_map_16bit(&images, &config).unwrap();
+    assert_eq!(normal_map.color(), image::ColorType::Rgb16);
+    // Matches the 8 bit map, with finer steps
+    let eight_bit = normals_from_shading::generate_normal_map(&images)
+        .unwrap()
+        .to_rgb8();
+    for (wide, narrow) in normal_map.to_rgb16().pixels().zip(eight_bit.pixels()) {
+        for channel in 0..3 {
+            let difference = (wide.0[channel] >> 8) as i32 - narrow.0[channel] as i32;
+            assert!(difference.abs() <= 1);
+        }
+    }
+}
+
+#[test]
+fn extreme_components_stay_in_range() {
+    let normals = NormalMatrix::from_row_slice(&[1.0, -1.0, 1.0, -1.0, 1.0, 0.0]);
+    assert_eq!(
+        normal_bytes(&normals, NormalConvention::DirectX),
+        vec![255, 0, 255, 0, 255, 128]
+    );
+}
+
+#[test]
+fn opengl_convention_flips_green() {
+    let images = sphere_images(32, &ring_lights(4));
+    let directx = normals_from_shading::generate_normal_map(&images).unwrap();
+    let config = normals_from_shading::NormalMapConfig {
+        image_options: NormalImageOptions {
+            convention: NormalConvention::OpenGl,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let opengl = normals_from_shading::generate_normal_map_with_config(&images, &config).unwrap();
+    for (a, b) in directx.to_rgb8().pixels().zip(opengl.to_rgb8().pixels()) {
+        assert_eq!((a.0[0], a.0[2]), (b.0[0], b.0[2]));
+        let green_sum = a.0[1] as i32 + b.0[1] as i32;
+        assert!((255..=256).contains(&green_sum), "{:?} {:?}", a, b);
+    }
+
+    let size = Vector2::new(16, 8);
+    let normals = random_normals(size.product(), 5);
+    assert_eq!(flip_green(&flip_green(&normals)), normals);
+    let flipped = normals_to_image(&flip_green(&normals), &size, Default::default()).unwrap();
+    let options = NormalImageOptions {
+        convention: NormalConvention::OpenGl,
+        ..Default::default()
+    };
+    let converted = normals_to_image(&normals, &size, options).unwrap();
+    assert_eq!(flipped.as_bytes(), converted.as_bytes());
+}
+
+#[test]
+fn relighting_shades_albedo_and_checks_sizes() {
+    let flat: Vec<f32> = [0.0, 0.0, 1.0].repeat(16);
+    let normals = NormalMatrix::from_row_slice(&flat);
+    let albedo: image::DynamicImage =
+        image::RgbaImage::from_pixel(4, 4, image::Rgba([100, 50, 0, 255])).into();
+
+    let facing = relight(&normals, &albedo, Vector3::z(), 0.0).unwrap();
+    assert_eq!(facing.to_rgba8().get_pixel(2, 2).0, [100, 50, 0, 255]);
+    // Only the ambient light reaches a surface lit edge on
+    let grazing = relight(&normals, &albedo, Vector3::x(), 0.5).unwrap();
+    assert_eq!(grazing.to_rgba8().get_pixel(2, 2).0, [50, 25, 0, 255]);
+
+    for (width, height) in [(5, 4), (3, 4)] {
+        let albedo: image::DynamicImage = image::RgbaImage::new(width, height).into();
+        assert!(relight(&normals, &albedo, Vector3::z(), 0.0).is_err());
+        assert!(relight_specular(&normals, &albedo, Vector3::z(), 0.0, 1.0, 8.0).is_err());
+    }
+}
+
+#[test]
+fn normal_difference_shows_changed_pixels() {
+    let size = Vector2::new(12, 10);
+    let normals = random_normals(size.product(), 9);
+    let same = normal_difference_image(&normals, &normals, &size).unwrap();
+    assert!(same.as_bytes().iter().all(|byte| *byte < 2));
+
+    // Turning every normal by the same angle about y changes those
+    // perpendicular to y equally
+    let in_plane: Vec<f32> = (0..size.product())
+        .flat_map(|i| {
+            let angle = i as f32 * 0.05 - 1.0;
+            [angle.sin(), 0.0, angle.cos()]
+        })
+        .collect();
+    let normals = NormalMatrix::from_row_slice(&in_plane);
+    let rotation = nalgebra::Rotation3::from_axis_angle(&nalgebra::Vector3::y_axis(), 0.3);
+    let mut rotated = normals.clone();
+    for mut normal in rotated.row_iter_mut() {
+        let turned = rotation * normal.transpose();
+        normal.copy_from(&turned.transpose());
+    }
+    let difference = normal_difference_image(&normals, &rotated, &size)
+        .unwrap()
+        .to_rgb8();
+    let first = difference.get_pixel(0, 0).0;
+    assert_ne!(first, [0, 0, 0]);
+    for pixel in difference.pixels() {
+        assert!(pixel.0.iter().zip(first).all(|(a, b)| a.abs_diff(b) <= 2));
+    }
+
+    assert!(normal_difference_image(&normals, &rotated, &Vector2::new(5, 5)).is_err());
+}
+
+#[test]
+fn axis_mapping_remaps_components() {
+    let size = Vector2::new(6, 4);
+    let normals = random_normals(size.product(), 13);
+    // z forward engines want the camera axis in the green channel
+    let swap_yz = nalgebra::Matrix3::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0);
+    let options = NormalImageOptions {
+        axes: Some(swap_yz),
+        ..Default::default()
+    };
+    let swapped = normals_to_image(&normals, &size, options)
+        .unwrap()
+        .to_rgb8();
+    let plain = normals_to_image(&normals, &size, Default::default())
+        .unwrap()
+        .to_rgb8();
+    for (a, b) in swapped.pixels().zip(plain.pixels()) {
+        assert_eq!(a.0, [b.0[0], b.0[2], b.0[1]]);
+    }
+
+    let squash = nalgebra::Matrix3::from_diagonal(&nalgebra::Vector3::new(1.0, 0.5, 1.0));
+    let invalid = NormalImageOptions {
+        axes: Some(squash),
+        ..Default::default()
+    };
+    assert!(normals_to_image(&normals, &size, invalid).is_err());
+}
+
+#[test]
+fn tone_mapped_hdr_preview_keeps_detail() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    // Bright enough that most of the sphere is above 1
+    let albedo = vec![4.0; size.product()];
+    let light = Vector3::new(0.3, 0.2, 1.0);
+    let radiance_map = render(&normals, &albedo, size, light);
+    assert!(radiance_map.radiance.max() > 3.0);
+
+    let sphere: Vec<usize> = (0..size.product()).filter(|i| covered[*i]).collect();
+    let white_fraction = |preview: &image::DynamicImage| {
+        let bytes = preview.to_luma8().into_raw();
+        sphere.iter().filter(|i| bytes[**i] == 255).count() as f32 / sphere.len() as f32
+    };
+    let clamped = radiance_preview(&radiance_map, PreviewOptions::default());
+    assert!(white_fraction(&clamped) > 0.8);
+    for tone_mapping in [ToneMapping::Reinhard, ToneMapping::Aces] {
+        let options = PreviewOptions {
+            tone_mapping,
+            ..Default::default()
+        };
+        let preview = radiance_preview(&radiance_map, options);
+        assert!(white_fraction(&preview) < 0.05, "{:?}", tone_mapping);
+        // Brighter radiance still previews brighter
+        let bytes = preview.to_luma8().into_raw();
+        let mut by_radiance = sphere.clone();
+        by_radiance.sort_by(|a, b| radiance_map.radiance[*a].total_cmp(&radiance_map.radiance[*b]));
+        assert!(by_radiance
+            .windows(2)
+            .all(|pair| bytes[pair[0]] <= bytes[pair[1]]));
+        let distinct: std::collections::HashSet<u8> = sphere.iter().map(|i| bytes[*i]).collect();
+        assert!(distinct.len() > 50, "{:?} {}", tone_mapping, distinct.len());
+    }
+
+    // The predicted shading previews the same as the radiance it predicts
+    let albedo = RadianceMatrix::from_row_slice(&albedo);
+    let options = PreviewOptions {
+        tone_mapping: ToneMapping::Reinhard,
+        srgb: true,
+    };
+    let shading = shading_preview(&normals, &albedo, &radiance_map, options);
+    assert_eq!(
+        shading.as_bytes(),
+        radiance_preview(&radiance_map, options).as_bytes()
+    );
+}
+
+#[test]
+fn obj_mesh_covers_the_height_field() {
+    let size = Vector2::new(4, 3);
+    let normals = random_normals(size.product(), 7);
+    let heights = nalgebra::DMatrix::from_fn(3, 4, |y, x| (x + y) as f32 / 5.0);
+    let config = MeshExportConfig {
+        xy_scale: 0.5,
+        z_scale: 10.0,
+    };
+    let mut obj = Vec::new();
+    write_obj(&heights, &normals, &size, None, &config, &mut obj).unwrap();
+    let obj = String::from_utf8(obj).unwrap();
+    let lines = |prefix: &str| -> Vec<String> {
+        obj.lines()
+            .filter(|line| line.starts_with(prefix))
+            .map(String::from)
+            .collect()
+    };
+    assert_eq!(lines("v ").len(), 12);
+    assert_eq!(lines("vn ").len(), 12);
+    // Two triangles for each of the 3x2 squares between pixels
+    assert_eq!(lines("f ").len(), 12);
+    // The last pixel is at x = 3, y = 2, with a height of 1
+    assert_eq!(lines("v ")[11], "v 1.5 -1 10");
+    let normal: Vec<f32> = lines("vn ")[5]
+        .split_whitespace()
+        .skip(1)
+        .map(|value| value.parse().unwrap())
+        .collect();
+    assert_eq!(normal, [normals[(5, 0)], -normals[(5, 1)], normals[(5, 2)]]);
+
+    // Masking out a corner drops its vertex and the triangle using it
+    let mut mask = vec![true; size.product()];
+    mask[0] = false;
+    let mut masked = Vec::new();
+    write_obj(&heights, &normals, &size, Some(&mask), &config, &mut masked).unwrap();
+    let masked = String::from_utf8(masked).unwrap();
+    assert_eq!(
+        masked.lines().filter(|line| line.starts_with("v ")).count(),
+        11
+    );
+    let faces: Vec<&str> = masked
+        .lines()
+        .filter(|line| line.starts_with("f "))
+        .collect();
+    assert_eq!(faces.len(), 10);
+    assert!(faces.iter().all(|face| face
+        .split_whitespace()
+        .skip(1)
+        .all(|vertex| (1..=11)
+            .contains(&vertex.split("//").next().unwrap().parse::<usize>().unwrap()))));
+
+    let wrong_size = Vector2::new(3, 4);
+    assert!(write_obj(
+        &heights,
+        &normals,
+        &wrong_size,
+        None,
+        &config,
+        &mut Vec::new()
+    )
+    .is_err());
+}
+
+#[cfg(feature = "exr")]
+#[test]
+fn exr_output_keeps_full_precision() {
+    let size = Vector2::new(6, 5);
+    let normals = random_normals(size.product(), 11);
+    let path = std::env::temp_dir().join("normals_from_shading_normals.exr");
+    let path = path.to_str().unwrap();
+    save_normals_exr(path, &normals, &size).unwrap();
+    let loaded = image::open(path).unwrap().to_rgb32f();
+    assert_eq!(loaded.dimensions(), (6, 5));
+    for (i, pixel) in loaded.pixels().enumerate() {
+        assert_eq!(pixel.0, [normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]]);
+    }
+    assert!(save_normals_exr(path, &normals, &Vector2::new(5, 5)).is_err());
+
+    // 8 bit sRGB albedo is decoded to linear
+    let albedo: image::DynamicImage =
+        image::RgbImage::from_pixel(4, 4, image::Rgb([255, 188, 0])).into();
+    let path = std::env::temp_dir().join("normals_from_shading_albedo.exr");
+    let path = path.to_str().unwrap();
+    save_albedo_exr(path, &albedo, true).unwrap();
+    let loaded = image::open(path).unwrap().to_rgb32f();
+    let expected = [
+        1.0,
+        normals_from_shading::radiance_map::srgb_to_linear(188.0 / 255.0),
+        0.0,
+    ];
+    assert!(loaded.pixels().all(|pixel| pixel.0 == expected));
+}
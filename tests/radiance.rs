@@ -0,0 +1,409 @@
+mod common;
+
+use common::*;
+use nalgebra::{DMatrix, Vector2};
+use normals_from_shading::albedo_utils;
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+
+#[test]
+fn demultiplexing_recovers_single_light_images() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.5; size.product()];
+    let lights = ring_lights(4);
+    let single_maps: Vec<_> = lights
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+
+    // Capture each image with three of the four lights on
+    let multiplexing = DMatrix::from_fn(4, 4, |i, j| if i == j { 0.0 } else { 1.0 });
+    let multiplexed_maps: Vec<_> = multiplexing
+        .row_iter()
+        .map(|weights| RadianceMap {
+            lighting_direction: nalgebra::Vector3::z(),
+            size,
+            radiance: single_maps
+                .iter()
+                .zip(weights.iter())
+                .map(|(map, weight)| &map.radiance * *weight)
+                .fold(RadianceMatrix::zeros(size.product()), |a, b| a + b),
+            mask: None,
+        })
+        .collect();
+
+    let demultiplexing = multiplexing.try_inverse().unwrap();
+    let mut recovered = demultiplex(&multiplexed_maps, &demultiplexing).unwrap();
+    for (recovered, single) in recovered.iter().zip(&single_maps) {
+        assert!((&recovered.radiance - &single.radiance).amax() < 1e-5);
+    }
+
+    // Solving with the recovered images matches solving with the originals
+    for (radiance_map, light) in recovered.iter_mut().zip(&lights) {
+        radiance_map.lighting_direction = *light;
+    }
+    let difference = generate_normals(&recovered) - generate_normals(&single_maps);
+    assert!(difference.amax() < 1e-3);
+}
+
+#[test]
+fn grayscale_loading_saves_memory() {
+    let path = "sample_input/tile_512_a.jpg";
+    let color = image::ImageReader::open(path).unwrap().decode().unwrap();
+    let grayscale = load_grayscale(path).unwrap();
+    assert_eq!(color.as_bytes().len(), 3 * grayscale.as_bytes().len());
+
+    // The normal map only uses brightness, so both give the same normals,
+    // up to rounding the brightness to 8 bits
+    let paths = ["sample_input/tile_512_b.jpg", "sample_input/tile_512_c.jpg"];
+    let crop = |image: image::DynamicImage| image.crop_imm(200, 200, 48, 48);
+    let mut color_images = vec![crop(color)];
+    let mut grayscale_images = vec![crop(grayscale)];
+    for path in paths {
+        color_images.push(crop(
+            image::ImageReader::open(path).unwrap().decode().unwrap(),
+        ));
+        grayscale_images.push(crop(load_grayscale(path).unwrap()));
+    }
+    let color_normals = normals_from_shading::generate_normal_map(&color_images).unwrap();
+    let grayscale_normals = normals_from_shading::generate_normal_map(&grayscale_images).unwrap();
+    let largest_difference = color_normals
+        .as_bytes()
+        .iter()
+        .zip(grayscale_normals.as_bytes())
+        .map(|(a, b)| a.abs_diff(*b))
+        .max();
+    assert!(largest_difference <= Some(4), "{:?}", largest_difference);
+}
+
+#[test]
+fn exposure_scale_matches_scaled_copy() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.9; size.product()];
+    let reference = render(&normals, &albedo, size, ring_lights(3)[0]);
+    let mut darker = render(&normals, &albedo, size, ring_lights(3)[0]);
+    darker.radiance *= 0.4;
+
+    let scale = best_exposure_scale(&darker, &[reference]);
+    assert!((scale - 2.5).abs() < 1e-4, "scale {}", scale);
+}
+
+#[test]
+fn radiance_uses_luma_weights() {
+    let image = image::RgbImage::from_fn(8, 8, |x, y| {
+        image::Rgb([(x * 30) as u8, (y * 30) as u8, ((x + y) * 15) as u8])
+    });
+    let radiance_map = RadianceMap::from(image::DynamicImage::from(image.clone()));
+    for (i, pixel) in image.pixels().enumerate() {
+        let expected: f32 = (0..3)
+            .map(|c| LUMA_WEIGHTS[c] * pixel.0[c] as f32 / 255.0)
+            .sum();
+        assert!((radiance_map.radiance[i] - expected).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn noisy_captures_get_more_smoothing() {
+    let clean: Vec<RadianceMap> = sphere_images(48, &ring_lights(4))
+        .into_iter()
+        .map(RadianceMap::from)
+        .collect();
+    let mut state = 7u64;
+    let mut random = || {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 40) as f32 / (1u64 << 24) as f32
+    };
+    let noisy: Vec<RadianceMap> = clean
+        .iter()
+        .map(|map| RadianceMap {
+            lighting_direction: map.lighting_direction,
+            size: map.size,
+            radiance: map
+                .radiance
+                .map(|radiance| radiance + 0.1 * (random() - 0.5)),
+            mask: None,
+        })
+        .collect();
+
+    let clean = recommend_iterations(&clean);
+    let noisy = recommend_iterations(&noisy);
+    assert!(noisy.noise > 5.0 * clean.noise, "{:?} {:?}", noisy, clean);
+    assert_eq!(clean.smoothing_iterations, 0);
+    assert!(noisy.smoothing_iterations > clean.smoothing_iterations);
+    assert!(noisy.refinement_iterations >= clean.refinement_iterations);
+}
+
+/// Encodes an image as a JPEG, tagged with the given EXIF orientation
+fn jpeg_with_orientation(image: &image::DynamicImage, orientation: u16) -> Vec<u8> {
+    let mut jpeg = Vec::<u8>::new();
+    image
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    // A little endian TIFF header, and a directory with one orientation entry
+    let mut exif = b"Exif\0\0II*\0\x08\0\0\0\x01\0\x12\x01\x03\0\x01\0\0\0".to_vec();
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&[0; 6]);
+    // Insert an APP1 segment after the start of image marker
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+    segment.extend_from_slice(&exif);
+    jpeg.splice(2..2, segment);
+    jpeg
+}
+
+#[test]
+fn exif_orientation_is_applied_on_load() {
+    let upright = image::DynamicImage::from(image::GrayImage::from_fn(24, 16, |x, y| {
+        image::Luma([(x * 8 + y * 3) as u8])
+    }));
+    let directory = std::env::temp_dir();
+    let upright_path = directory.join("normals_from_shading_upright.jpg");
+    let rotated_path = directory.join("normals_from_shading_rotated.jpg");
+    std::fs::write(&upright_path, jpeg_with_orientation(&upright, 1)).unwrap();
+    // Stored on its side, with orientation 6 saying to turn it clockwise
+    std::fs::write(
+        &rotated_path,
+        jpeg_with_orientation(&upright.rotate270(), 6),
+    )
+    .unwrap();
+
+    let upright = load_oriented(upright_path.to_str().unwrap()).unwrap();
+    let rotated = load_oriented(rotated_path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&upright_path).unwrap();
+    std::fs::remove_file(&rotated_path).unwrap();
+
+    assert_eq!((rotated.width(), rotated.height()), (24, 16));
+    let upright = RadianceMap::from(upright);
+    let rotated = RadianceMap::from(rotated);
+    assert!((upright.radiance - rotated.radiance).amax() < 0.05);
+}
+
+#[test]
+fn light_color_is_removed_before_greyscale() {
+    // A neutral grey surface with the same shading under a warm and a
+    // cool light of equal strength
+    let shaded = |tint: [f32; 3]| {
+        image::DynamicImage::from(image::RgbImage::from_fn(16, 16, |x, y| {
+            let shading = 0.3 + 0.02 * (x + y) as f32;
+            image::Rgb(tint.map(|channel| (shading * channel * 255.0).round() as u8))
+        }))
+    };
+    let warm = shaded([1.0, 0.8, 0.6]);
+    let cool = shaded([0.6, 0.8, 1.0]);
+
+    let uncorrected =
+        RadianceMap::from(warm.clone()).radiance - RadianceMap::from(cool.clone()).radiance;
+    assert!(uncorrected.amax() > 0.05);
+    let corrected =
+        radiance_without_light_color(&warm).radiance - radiance_without_light_color(&cool).radiance;
+    assert!(corrected.amax() < 0.01, "{}", corrected.amax());
+}
+
+#[test]
+fn corner_flattening_removes_per_image_gradients() {
+    let size = Vector2::new(64, 48);
+    // h(x, y) = 2 sin(x / 6) cos(y / 7), a bumpy tile
+    let normals = height_field_normals(size, |x, y| {
+        (
+            2.0 / 6.0 * (x / 6.0).cos() * (y / 7.0).cos(),
+            -2.0 / 7.0 * (x / 6.0).sin() * (y / 7.0).sin(),
+        )
+    });
+    let albedo = vec![0.6; size.product()];
+    // Each light is nearby, so the image brightens towards its side
+    let images: Vec<_> = ring_lights(4)
+        .into_iter()
+        .map(|light| {
+            let mut radiance_map = render(&normals, &albedo, size, light);
+            for y in 0..size[1] {
+                for x in 0..size[0] {
+                    let offset = light.x * (x as f32 / size[0] as f32 - 0.5)
+                        + light.y * (y as f32 / size[1] as f32 - 0.5);
+                    radiance_map.radiance[y * size[0] + x] *= 1.0 + 1.5 * offset;
+                }
+            }
+            radiance_image(&radiance_map)
+        })
+        .collect();
+
+    let pixels: Vec<usize> = (0..size.product()).collect();
+    let error = |flatten_radiance| {
+        let config = normals_from_shading::NormalMapConfig {
+            flatten_radiance,
+            ..Default::default()
+        };
+        let output = normals_from_shading::solve_all(&images, &config).unwrap();
+        mean_angle_error(&output.normals, &normals, &pixels)
+    };
+    let (plain_error, flattened_error) = (error(false), error(true));
+    assert!(
+        flattened_error < 0.7 * plain_error,
+        "error went from {} to {}",
+        plain_error,
+        flattened_error
+    );
+}
+
+#[cfg(feature = "exr")]
+#[test]
+fn float_images_keep_high_dynamic_range() {
+    let bright = image::Rgb32FImage::from_fn(4, 2, |x, _| image::Rgb([x as f32, 2.0, 0.5]));
+    let path = std::env::temp_dir().join("normals_from_shading_hdr.exr");
+    image::DynamicImage::from(bright.clone())
+        .save(&path)
+        .unwrap();
+    let loaded = RadianceMap::load(path.to_str().unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let radiance_map = RadianceMap::from(image::DynamicImage::from(bright));
+    assert_eq!(radiance_map.radiance, loaded.radiance);
+    for x in 0..4 {
+        let expected = x as f32 * LUMA_WEIGHTS[0] + 2.0 * LUMA_WEIGHTS[1] + 0.5 * LUMA_WEIGHTS[2];
+        assert!((radiance_map.radiance[x] - expected).abs() < 1e-5);
+    }
+    assert!(radiance_map.radiance.max() > 2.0);
+}
+
+#[test]
+fn preprocessing_steps_apply_in_order() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let render_maps = || -> Vec<RadianceMap> {
+        // Lights at different tilts, so the maps differ by more than scale
+        [(0.0, 0.9), (0.6, 0.5), (0.9, 0.3)]
+            .into_iter()
+            .map(|(x, albedo)| {
+                let albedo = vec![albedo; size.product()];
+                render(&normals, &albedo, size, nalgebra::Vector3::new(x, 0.0, 1.0))
+            })
+            .collect()
+    };
+    let means =
+        |maps: &[RadianceMap]| -> Vec<f32> { maps.iter().map(|map| map.radiance.mean()).collect() };
+
+    // Linearizing first balances the linear brightness
+    let mut linear_first = render_maps();
+    Preprocessing::default()
+        .then(PreprocessStep::Linearize)
+        .then(PreprocessStep::Balance)
+        .apply(&mut linear_first)
+        .unwrap();
+    let linear_means = means(&linear_first);
+    assert!(linear_means
+        .iter()
+        .all(|mean| (mean - linear_means[0]).abs() < 1e-4));
+
+    // Balancing first matches the encoded brightness, which
+    // linearizing then makes unequal again
+    let mut balanced_first = render_maps();
+    Preprocessing::default()
+        .then(PreprocessStep::Balance)
+        .then(PreprocessStep::Linearize)
+        .apply(&mut balanced_first)
+        .unwrap();
+    let balanced_means = means(&balanced_first);
+    assert!(balanced_means
+        .iter()
+        .any(|mean| (mean - balanced_means[0]).abs() > 1e-3));
+    assert_ne!(linear_first[1].radiance, balanced_first[1].radiance);
+
+    let mut maps = render_maps();
+    let mismatched = Preprocessing::default().then(PreprocessStep::Exposures(vec![None]));
+    assert!(mismatched.apply(&mut maps).is_err());
+}
+
+#[test]
+fn transparent_pixels_are_masked_out() {
+    let width = 32;
+    let lights = ring_lights(4);
+    // Matte out the left half of the sphere
+    let images: Vec<image::DynamicImage> = sphere_images(width, &lights)
+        .iter()
+        .map(|image| {
+            let mut rgba = image.to_rgba8();
+            for (x, _, pixel) in rgba.enumerate_pixels_mut() {
+                if (x as usize) < width / 2 {
+                    pixel.0[3] = 0;
+                }
+            }
+            rgba.into()
+        })
+        .collect();
+    let radiance_maps: Vec<RadianceMap> = images
+        .into_iter()
+        .zip(&lights)
+        .map(|(image, light)| {
+            let mut radiance_map = RadianceMap::from(image);
+            radiance_map.lighting_direction = *light;
+            radiance_map
+        })
+        .collect();
+    let mask = radiance_maps[0].mask.as_ref().unwrap();
+    assert!(!mask[width * 16 + 8] && mask[width * 16 + 24]);
+
+    let normals = generate_normals(&radiance_maps);
+    let (expected, covered) = sphere_normals(width);
+    let lit: Vec<usize> = lit_pixels(&covered, &radiance_maps)
+        .into_iter()
+        .filter(|pixel| mask[*pixel])
+        .collect();
+    assert!(mean_angle_error(&normals, &expected, &lit) < 3.0);
+    assert!((0..width * width)
+        .filter(|pixel| !mask[*pixel])
+        .all(|pixel| normals.row(pixel) == nalgebra::RowVector3::new(0.0, 0.0, 1.0)));
+    assert!(albedo_utils::solve_albedo(&radiance_maps)
+        .iter()
+        .take(width / 2)
+        .all(|albedo| *albedo == 0.0));
+
+    // Opaque images have no mask
+    assert!(RadianceMap::from(sphere_images(width, &lights).remove(0))
+        .mask
+        .is_none());
+}
+
+#[test]
+fn linearized_images_recover_linear_radiance() {
+    for i in 0..=100 {
+        let value = i as f32 / 100.0;
+        assert!((srgb_to_linear(linear_to_srgb(value)) - value).abs() < 1e-5);
+    }
+
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.8; size.product()];
+    let linear = render(
+        &normals,
+        &albedo,
+        size,
+        nalgebra::Vector3::new(0.4, 0.2, 1.0),
+    );
+    // Store the shading as an 8 bit sRGB image, as a camera would
+    let encoded: image::DynamicImage =
+        image::RgbImage::from_fn(width as u32, width as u32, |x, y| {
+            let value = linear.radiance[y as usize * width + x as usize];
+            image::Rgb([(linear_to_srgb(value) * 255.0).round() as u8; 3])
+        })
+        .into();
+
+    let largest_error =
+        |radiance_map: &RadianceMap| (&radiance_map.radiance - &linear.radiance).abs().max();
+    let decoded = RadianceMap::from(linearize_image(&encoded));
+    assert!(
+        largest_error(&decoded) < 0.01,
+        "{}",
+        largest_error(&decoded)
+    );
+    assert!(largest_error(&RadianceMap::from(encoded)) > 0.1);
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use common::*;
+use nalgebra::Vector2;
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::*;
+use normals_from_shading::residual_utils::*;
+
+#[test]
+fn interreflections_are_flagged() {
+    let width = 64;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.5; size.product()];
+    let in_cavity = |i: usize| (20..30).contains(&(i % width)) && (20..30).contains(&(i / width));
+
+    // Add light bounced from elsewhere in the scene to part of the image
+    let radiance_maps: Vec<_> = ring_lights(6)
+        .into_iter()
+        .map(|light| {
+            let mut radiance_map = render(&normals, &albedo, size, light);
+            for i in (0..size.product()).filter(|i| in_cavity(*i)) {
+                radiance_map.radiance[i] += 0.1;
+            }
+            radiance_map
+        })
+        .collect();
+
+    let albedo = RadianceMatrix::from_row_slice(&albedo);
+    let residuals = reconstruction_residuals(&normals, &albedo, &radiance_maps);
+    let flagged = detect_interreflections(&residuals, 0.05, 0.8);
+    for (i, flagged) in flagged.iter().enumerate() {
+        assert_eq!(*flagged, in_cavity(i), "pixel {}", i);
+    }
+
+    let confidence = confidence_map(&residuals, &flagged);
+    for i in 0..size.product() {
+        if in_cavity(i) {
+            assert_eq!(confidence[i], 0.0);
+        } else {
+            assert!(confidence[i] > 0.99);
+        }
+    }
+}
+
+#[test]
+fn brighter_pixels_have_larger_magnitudes() {
+    // Two pixels with the same normal, but different albedo
+    let size = Vector2::new(2, 1);
+    let normals = NormalMatrix::from_row_slice(&[0.0, 0.0, 1.0, 0.0, 0.0, 1.0]);
+    let albedo = [0.9, 0.3];
+    let radiance_maps: Vec<RadianceMap> = ring_lights(4)
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    let magnitudes = least_squares_magnitudes(&radiance_maps, &SolveOptions::default());
+    assert!(magnitudes[0] > magnitudes[1]);
+    assert!((magnitudes[0] - 0.9).abs() < 1e-3);
+
+    let config = normals_from_shading::NormalMapConfig {
+        magnitude_map: true,
+        ..Default::default()
+    };
+    let images = sphere_images(32, &ring_lights(4));
+    let result = normals_from_shading::generate_normal_map_result(&images, &config).unwrap();
+    let magnitude_map = result.magnitude_map.unwrap();
+    assert_eq!((magnitude_map.width(), magnitude_map.height()), (32, 32));
+}
+
+#[test]
+fn residual_images_show_corrupted_regions() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.6; size.product()];
+    let in_region = |i: usize| (4..12).contains(&(i % width)) && (18..26).contains(&(i / width));
+
+    let mut radiance_maps: Vec<_> = ring_lights(4)
+        .into_iter()
+        .map(|light| render(&normals, &albedo, size, light))
+        .collect();
+    // Corrupt part of the second image only
+    for i in (0..size.product()).filter(|i| in_region(*i)) {
+        radiance_maps[1].radiance[i] = 1.0;
+    }
+
+    let albedo = RadianceMatrix::from_row_slice(&albedo);
+    let images = per_light_residual_images(&normals, &albedo, &radiance_maps);
+    assert_eq!(images.len(), 4);
+    for (light, image) in images.iter().enumerate() {
+        for (i, pixel) in image.to_luma8().pixels().enumerate() {
+            if light == 1 && in_region(i) {
+                assert!(pixel.0[0] > 50, "pixel {}", i);
+            } else {
+                assert!(pixel.0[0] <= 1, "light {} pixel {}", light, i);
+            }
+        }
+    }
+}
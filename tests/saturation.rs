@@ -0,0 +1,253 @@
+mod common;
+
+use common::*;
+use nalgebra::{DVector, Vector2, Vector3};
+use normals_from_shading::normal_utils::*;
+use normals_from_shading::radiance_map::RadianceMatrix;
+
+#[test]
+fn each_pixel_skips_its_own_saturated_images() {
+    let width = 48;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.6; size.product()];
+    let lights = ring_lights(6);
+    let band = |i: usize| (i % width) / (width / 6);
+
+    // A highlight moves across the image, clipping a different band each time
+    let clean_maps: Vec<_> = lights
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    // Pixels bright enough not to count as shadowed under any light
+    let lit: Vec<usize> = lit_pixels(&covered, &clean_maps)
+        .into_iter()
+        .filter(|&i| clean_maps.iter().all(|map| map.radiance[i] >= 0.05))
+        .collect();
+    let mut clipped_maps: Vec<_> = lights
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    for (k, radiance_map) in clipped_maps.iter_mut().enumerate() {
+        for i in (0..size.product()).filter(|i| band(*i) == k) {
+            radiance_map.radiance[i] = 1.0;
+        }
+    }
+
+    for &i in &lit {
+        let observations = valid_observations(&clipped_maps, i, &SolveOptions::default());
+        let expected: Vec<usize> = (0..6).filter(|k| *k != band(i)).collect();
+        assert_eq!(observations, expected);
+    }
+
+    let (solved, stats) = generate_normals_with_options(&clipped_maps, &SolveOptions::default());
+    assert!(stats
+        .fallback_pixels
+        .iter()
+        .all(|pixel| !lit.contains(pixel)));
+    assert!(mean_angle_error(&solved, &normals, &lit) < 0.1);
+
+    let keep_all = SolveOptions {
+        specular_threshold: f32::INFINITY,
+        shadow_threshold: f32::NEG_INFINITY,
+        confidence_weighting: false,
+        ..Default::default()
+    };
+    let (naive, _) = generate_normals_with_options(&clipped_maps, &keep_all);
+    assert!(mean_angle_error(&naive, &normals, &lit) > 5.0);
+}
+
+#[test]
+fn ransac_ignores_outlier_observations() {
+    let normal = Vector3::new(0.2, -0.3, 0.9).normalize() * 0.7;
+    let lights = ring_lights(8);
+    let a = NormalMatrix::from_fn(lights.len(), |row, axis| lights[row][axis]);
+    let mut b = RadianceMatrix::from_fn(lights.len(), |row, _| lights[row].dot(&normal));
+    // A specular highlight in one image
+    b[2] += 0.5;
+
+    let plain = least_squares(&a, &b).unwrap();
+    let robust = least_squares_ransac(&a, &b, 50, 0.01).unwrap();
+    assert!((plain - normal).norm() > 0.05);
+    assert!((robust - normal).norm() < 1e-4);
+
+    let two_rows = NormalMatrix::from_fn(2, |row, axis| lights[row][axis]);
+    assert!(least_squares_ransac(&two_rows, &b.rows(0, 2).into_owned(), 50, 0.01).is_none());
+}
+
+#[test]
+fn specular_observations_are_dropped_by_default() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, covered) = sphere_normals(width);
+    let albedo = vec![0.6; size.product()];
+    let mut radiance_maps: Vec<_> = ring_lights(5)
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    // A highlight in one image, and a pixel blown out in all but two
+    let highlighted = width * width / 2 + width / 2;
+    let blown_out = highlighted + 1;
+    assert!(covered[highlighted] && covered[blown_out]);
+    radiance_maps[0].radiance[highlighted] = 0.97;
+    for radiance_map in &mut radiance_maps[2..] {
+        radiance_map.radiance[blown_out] = 0.99;
+    }
+
+    let (solved, stats) = generate_normals_with_options(&radiance_maps, &SolveOptions::default());
+    assert!(stats.fallback_pixels.contains(&blown_out));
+    assert!(!stats.fallback_pixels.contains(&highlighted));
+    assert_eq!(stats.fallback_count, stats.fallback_pixels.len());
+    assert!(mean_angle_error(&solved, &normals, &[highlighted]) < 0.1);
+}
+
+#[test]
+fn shadowed_observations_are_dropped() {
+    let width = 32;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.6; size.product()];
+    let mut radiance_maps: Vec<_> = ring_lights(5)
+        .iter()
+        .map(|light| render(&normals, &albedo, size, *light))
+        .collect();
+    // A cast shadow over the center pixel in one image
+    let pixel = width * width / 2 + width / 2;
+    radiance_maps[3].radiance[pixel] = 0.01;
+
+    let options = SolveOptions::default();
+    assert_eq!(
+        valid_observations(&radiance_maps, pixel, &options),
+        vec![0, 1, 2, 4]
+    );
+    let (solved, _) = generate_normals_with_options(&radiance_maps, &options);
+    assert!(mean_angle_error(&solved, &normals, &[pixel]) < 0.1);
+
+    let keep_shadows = SolveOptions {
+        shadow_threshold: 0.0,
+        confidence_weighting: false,
+        ..Default::default()
+    };
+    let (naive, _) = generate_normals_with_options(&radiance_maps, &keep_shadows);
+    assert!(mean_angle_error(&naive, &normals, &[pixel]) > 5.0);
+}
+
+#[test]
+fn underconstrained_pixels_face_forwards() {
+    let width = 16;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let albedo = vec![0.7; size.product()];
+    let two_maps: Vec<_> = ring_lights(2)
+        .into_iter()
+        .map(|light| render(&normals, &albedo, size, light))
+        .collect();
+    let (solved, stats) = generate_normals_with_stats(&two_maps);
+    assert_eq!(stats.fallback_count, size.product());
+    assert!(solved
+        .row_iter()
+        .all(|normal| normal == Vector3::z().transpose()));
+
+    // Images that all share one light direction are degenerate too
+    let degenerate: Vec<_> = [-0.5, 0.0, 0.5]
+        .into_iter()
+        .map(|x| render(&normals, &albedo, size, Vector3::new(x, 0.0, 1.0)))
+        .map(|mut map| {
+            map.lighting_direction = Vector3::new(1.0, 0.0, 0.0);
+            map
+        })
+        .collect();
+    let (_, stats) = generate_normals_with_stats(&degenerate);
+    assert_eq!(stats.fallback_count, size.product());
+
+    let (empty, stats) = generate_normals_with_stats(&[]);
+    assert_eq!((empty.nrows(), stats.pixel_count), (0, 0));
+}
+
+#[test]
+fn svd_solver_handles_nearly_coplanar_lights() {
+    // Lights barely above the horizon, so their directions are close
+    // to coplanar
+    let lights = NormalMatrix::from_row_slice(&[
+        1.0, 0.0, 0.002, //
+        -0.5, 0.866, 0.0021, //
+        -0.5, -0.866, 0.0019, //
+        0.7, 0.7, 0.002,
+    ]);
+    for normal in random_normals(200, 21).row_iter() {
+        let radiance: RadianceMatrix = &lights * normal.transpose();
+        let solved = least_squares_svd(&lights, &radiance).unwrap();
+        assert!(solved.normalize().angle(&normal.transpose()).to_degrees() < 0.1);
+    }
+
+    // Lights in a tilted plane only look independent through rounding,
+    // and are rejected rather than solved
+    let in_plane: Vec<f32> = [[1.0f32, -1.0, 0.0], [0.0, 1.0, -1.0], [1.0, 0.0, -1.0]]
+        .into_iter()
+        .flat_map(|light| Vector3::from(light).normalize().data.0[0])
+        .collect();
+    let coplanar = NormalMatrix::from_row_slice(&in_plane);
+    let radiance = RadianceMatrix::from_row_slice(&[0.3, 0.2, 0.5]);
+    assert!(least_squares_svd(&coplanar, &radiance).is_none());
+
+    // Two lights can't fix a normal, even though their SVD is well
+    // conditioned
+    let two = NormalMatrix::from_row_slice(&[0.0, 0.0, 1.0, 0.6, 0.0, 0.8]);
+    let radiance = RadianceMatrix::from_row_slice(&[0.5, 0.4]);
+    assert!(least_squares(&two, &radiance).is_none());
+    assert!(least_squares_svd(&two, &radiance).is_none());
+
+    // Both solvers agree on well conditioned lights
+    let options = SolveOptions {
+        solver: LeastSquaresSolver::Svd,
+        ..Default::default()
+    };
+    let width = 24;
+    let size = Vector2::new(width, width);
+    let (normals, _) = sphere_normals(width);
+    let maps: Vec<_> = ring_lights(4)
+        .into_iter()
+        .map(|light| render(&normals, &vec![0.6; size.product()], size, light))
+        .collect();
+    let (svd, _) = generate_normals_with_options(&maps, &options);
+    let (normal_equations, _) = generate_normals_with_options(&maps, &SolveOptions::default());
+    assert!((svd - normal_equations).amax() < 1e-4);
+}
+
+#[test]
+fn weighted_least_squares_trusts_heavy_rows() {
+    let a = NormalMatrix::from_row_slice(&[
+        1.0, 0.0, 0.0, //
+        0.0, 1.0, 0.0, //
+        0.0, 0.0, 1.0, //
+        0.6, 0.0, 0.8,
+    ]);
+    let exact = Vector3::new(0.2, 0.3, 0.9);
+    let mut b = &a * exact;
+    // Corrupt the last observation
+    b[3] += 0.5;
+
+    let uniform = DVector::from_element(4, 1.0);
+    assert_eq!(
+        least_squares_weighted(&a, &b, &uniform),
+        least_squares(&a, &b)
+    );
+    let trusted = DVector::from_row_slice(&[1.0, 1.0, 1.0, 0.0]);
+    for solver in [LeastSquaresSolver::NormalEquations, LeastSquaresSolver::Svd] {
+        let solved = solver.solve_weighted(&a, &b, &trusted).unwrap();
+        assert!((solved - exact).norm() < 1e-5, "{:?} {}", solver, solved);
+    }
+    let plain = least_squares(&a, &b).unwrap();
+    let down_weighted = DVector::from_row_slice(&[1.0, 1.0, 1.0, 0.1]);
+    let weighted = least_squares_weighted(&a, &b, &down_weighted).unwrap();
+    assert!((weighted - exact).norm() < 0.5 * (plain - exact).norm());
+}
+
+#[test]
+fn radiance_confidence_peaks_at_mid_grey() {
+    assert_eq!(radiance_confidence(0.5), 1.0);
+    assert!(radiance_confidence(0.3) > radiance_confidence(0.1));
+    assert!((radiance_confidence(0.2) - radiance_confidence(0.8)).abs() < 1e-6);
+    assert_eq!(radiance_confidence(0.0), MIN_RADIANCE_CONFIDENCE);
+    assert_eq!(radiance_confidence(2.0), MIN_RADIANCE_CONFIDENCE);
+}
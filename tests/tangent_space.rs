@@ -0,0 +1,72 @@
+mod common;
+
+use common::*;
+use nalgebra::{Rotation3, Vector3};
+use normals_from_shading::normal_utils::*;
+
+fn repeat(vector: Vector3<f32>, count: usize) -> NormalMatrix {
+    NormalMatrix::from_fn(count, |_, axis| vector[axis])
+}
+
+#[test]
+fn identity_basis_keeps_normals() {
+    let normals = random_normals(100, 5);
+    let tangent_normals = to_tangent_space(
+        &normals,
+        &repeat(Vector3::x(), 100),
+        &repeat(Vector3::y(), 100),
+        &repeat(Vector3::z(), 100),
+    );
+    assert!((tangent_normals - normals).amax() < 1e-6);
+}
+
+#[test]
+fn rotated_basis_transforms_normals() {
+    let normals = random_normals(100, 9);
+    let rotation = Rotation3::from_euler_angles(0.3, -0.2, 0.7);
+    let tangent_normals = to_tangent_space(
+        &normals,
+        &repeat(rotation * Vector3::x(), 100),
+        &repeat(rotation * Vector3::y(), 100),
+        &repeat(rotation * Vector3::z(), 100),
+    );
+    for i in 0..100 {
+        let normal = Vector3::new(normals[(i, 0)], normals[(i, 1)], normals[(i, 2)]);
+        let expected = rotation.inverse() * normal;
+        let actual = tangent_normals.row(i).transpose();
+        assert!((actual - expected).amax() < 1e-5);
+    }
+}
+
+#[test]
+fn reorienting_balanced_normals_keeps_them() {
+    // A symmetric bump: every tilted normal has an opposite partner,
+    // so the average normal is zero
+    let tilt = 0.5f32.sqrt();
+    let normals = NormalMatrix::from_row_slice(&[
+        tilt, 0.0, -tilt, //
+        -tilt, 0.0, tilt, //
+        0.0, tilt, -tilt, //
+        0.0, -tilt, tilt,
+    ]);
+    let reoriented = reorient_normals(&normals);
+    assert!(reoriented.iter().all(|value| value.is_finite()));
+    assert_eq!(reoriented, normals);
+}
+
+#[test]
+fn hemisphere_clamp_fixes_back_facing_normals() {
+    let forward = Vector3::new(0.3, -0.4, 0.866).normalize();
+    let mut normals = repeat(forward, 4);
+    // Plant a back-facing normal
+    let backward = Vector3::new(0.6, 0.0, -0.8);
+    normals.set_row(2, &backward.transpose());
+
+    let clamped = clamp_to_hemisphere(&normals);
+    assert!(clamped.column(2).iter().all(|z| *z >= 0.0));
+    let fixed = Vector3::new(clamped[(2, 0)], clamped[(2, 1)], clamped[(2, 2)]);
+    assert!((fixed - Vector3::x()).norm() < 1e-6);
+    for row in [0, 1, 3] {
+        assert_eq!(clamped.row(row), normals.row(row));
+    }
+}
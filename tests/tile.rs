@@ -5,7 +5,7 @@ use normals_from_shading::*;
 fn normal_map_generation() {
     let mut images = Vec::<DynamicImage>::new();
 
-    let args = vec![
+    let args = [
         "sample_input/tile_512_a.jpg",
         "sample_input/tile_512_b.jpg",
         "sample_input/tile_512_c.jpg",
@@ -14,9 +14,9 @@ fn normal_map_generation() {
     // Load images
     for path in &args[1..] {
         let image = ImageReader::open(path)
-            .expect(&format!("Could not open image: {}", path))
+            .unwrap_or_else(|_| panic!("Could not open image: {}", path))
             .decode()
-            .expect(&format!("Could not decode image: {}", path));
+            .unwrap_or_else(|_| panic!("Could not decode image: {}", path));
         images.push(image);
     }
 
@@ -27,7 +27,7 @@ fn normal_map_generation() {
 fn albedo_generation() {
     let mut images = Vec::<DynamicImage>::new();
 
-    let args = vec![
+    let args = [
         "sample_input/tile_512_a.jpg",
         "sample_input/tile_512_b.jpg",
         "sample_input/tile_512_c.jpg",
@@ -36,9 +36,9 @@ fn albedo_generation() {
     // Load images
     for path in &args[1..] {
         let image = ImageReader::open(path)
-            .expect(&format!("Could not open image: {}", path))
+            .unwrap_or_else(|_| panic!("Could not open image: {}", path))
             .decode()
-            .expect(&format!("Could not decode image: {}", path));
+            .unwrap_or_else(|_| panic!("Could not decode image: {}", path));
         images.push(image);
     }
 